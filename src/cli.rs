@@ -2,7 +2,12 @@ use std::path::PathBuf;
 
 use clap::{Parser, Subcommand};
 
-use crate::conf::{DEFAULT_RELAY, DEFAULT_BIND, DEFAULT_MAX_CLIENTS, DEFAULT_PEER_TIMEOUT_SECS};
+use crate::conf::{
+    DEFAULT_BIND, DEFAULT_HANDSHAKE_TIMEOUT_SECS, DEFAULT_MAX_CLIENTS, DEFAULT_MAX_HANDSHAKES,
+    DEFAULT_PEER_TIMEOUT_SECS, DEFAULT_RELAY, MAX_STREAMS,
+};
+use crate::file::{CollisionPolicy, SymlinkMode};
+use crate::password::CodeFormat;
 
 /// E2E encrypted file transfer via relay
 #[derive(Parser, Debug)]
@@ -15,26 +20,73 @@ pub struct Cli {
 
 #[derive(Subcommand, Debug)]
 pub enum Commands {
-    /// Send file(s). Can provide optional password
+    /// Send file(s) or text. Can provide optional password
     Send {
         /// Optional password (if not provided, one will be generated)
         #[clap(long, value_parser, required = false)]
         password: Option<String>,
+        /// Read the password from a file instead
+        #[clap(long, value_parser, conflicts_with = "password")]
+        password_file: Option<PathBuf>,
+        /// Format of the generated password
+        #[clap(
+            long,
+            value_parser,
+            default_value = "words",
+            conflicts_with_all = &["password", "password-file"]
+        )]
+        code_format: CodeFormat,
         /// Relay server address
         #[clap(long, value_parser, default_value = DEFAULT_RELAY)]
         relay: String,
-        /// Paths to files to be sent
-        #[clap(value_parser, required = true)]
+        /// Send a text message instead of files. Reads from stdin if no text is given
+        #[clap(long, value_parser, conflicts_with = "paths")]
+        text: Option<Option<String>>,
+        /// Number of parallel connections to spread files across
+        #[clap(long, value_parser = clap::value_parser!(u16).range(1..=MAX_STREAMS as i64), default_value = "1")]
+        streams: u16,
+        /// How to handle symlinks found inside directories
+        #[clap(long, value_parser, default_value = "follow")]
+        symlinks: SymlinkMode,
+        /// Wait for you to compare the verification code with the receiver's before sending
+        #[clap(long, action)]
+        verify: bool,
+        /// Paths to files or directories to be sent. Use - to read from stdin
+        #[clap(value_parser, required_unless_present = "text")]
         paths: Vec<PathBuf>,
     },
     /// Receive file(s). Must provide password shared out of band
     Receive {
-        /// Password shared by sender
-        #[clap(value_parser, required = true)]
-        password: String,
+        /// Password shared by sender, else read from --password-file, RUCK_PASSWORD or a prompt
+        #[clap(value_parser, required = false)]
+        password: Option<String>,
+        /// Read the password from a file instead
+        #[clap(long, value_parser, conflicts_with = "password")]
+        password_file: Option<PathBuf>,
         /// Relay server address
         #[clap(long, value_parser, default_value = DEFAULT_RELAY)]
         relay: String,
+        /// Write received data to stdout instead of files
+        #[clap(long, action)]
+        stdout: bool,
+        /// Directory to save received files in
+        #[clap(long, value_parser, default_value = ".", conflicts_with = "stdout")]
+        out: PathBuf,
+        /// How to handle files that already exist in the output directory
+        #[clap(long, value_parser, default_value = "rename")]
+        collision: CollisionPolicy,
+        /// Don't apply the sender's file permissions and modification times
+        #[clap(long, action)]
+        no_preserve: bool,
+        /// Only transfer the changed parts of files that already exist
+        #[clap(long, action)]
+        delta: bool,
+        /// Wait for you to compare the verification code with the sender's before receiving
+        #[clap(long, action)]
+        verify: bool,
+        /// Accept all offered files without asking, e.g. when stdin isn't a terminal
+        #[clap(short, long, action)]
+        yes: bool,
     },
     /// Start relay server
     Relay {
@@ -47,5 +99,11 @@ pub enum Commands {
         /// Timeout in seconds for peer matching
         #[clap(long, value_parser, default_value_t = DEFAULT_PEER_TIMEOUT_SECS)]
         timeout: u64,
+        /// Seconds a new connection has to send its handshake before it is dropped
+        #[clap(long, value_parser, default_value_t = DEFAULT_HANDSHAKE_TIMEOUT_SECS)]
+        handshake_timeout: u64,
+        /// Maximum connections still waiting for their handshake
+        #[clap(long, value_parser, default_value_t = DEFAULT_MAX_HANDSHAKES)]
+        max_handshakes: usize,
     },
 }
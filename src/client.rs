@@ -1,31 +1,48 @@
-use crate::conf::DEFAULT_RELAY;
+use crate::conf::{DEFAULT_RELAY, DELTA_MIN_BLOCK_SIZE, MAX_STREAMS, PART_SUFFIX};
 use crate::connection::Connection;
-use crate::file::{ChunkHeader, CompressionType, FileHandle, FileOffer, StdFileHandle};
+use crate::crypto::{Cipher, ConnectionKeys, Role};
+use crate::delta::Signatures;
+use crate::file::{
+    append_suffix, to_size_string, ChunkRequest, CollisionPolicy, EntryKind, FileHandle, FileId,
+    FileMetadata, FileOffer, StdFileHandle, SymlinkMode,
+};
 use crate::handshake::Handshake;
-use crate::message::{FileOfferPayload, FileRequestPayload, Message};
-use crate::password::validate_generate_pw;
-use crate::ui::prompt_user_for_file_confirmation;
+use crate::hash::{finalize, hash_file_prefix, FileDigest, Hasher};
+use crate::message::{
+    DigestRequestPayload, DigestsPayload, FileOfferPayload, FileRequestPayload,
+    FileSignaturesPayload, Message, TextPayload,
+};
+use crate::sanitize::{ensure_link_within, ensure_parent_within, ensure_within, sanitize_offers};
+use crate::ui::{confirm_verification_code, print_session_info, prompt_user_for_file_confirmation};
 
 use anyhow::{anyhow, Context, Result};
 use colored::Colorize;
 
-use std::path::PathBuf;
-use tokio::fs::{File, OpenOptions};
+use std::collections::{HashMap, HashSet};
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use tokio::fs::OpenOptions;
 
 use tokio::net::TcpStream;
 use tracing::{debug, error};
 
-pub async fn send(file_paths: &Vec<PathBuf>, password: &Option<String>, relay: &str) -> Result<()> {
+pub async fn send(
+    file_paths: &[PathBuf],
+    text: &Option<String>,
+    symlinks: SymlinkMode,
+    streams: u16,
+    verify: bool,
+    pw: &str,
+    relay: &str,
+) -> Result<()> {
+    if verify && file_paths.iter().any(|path| path.as_os_str() == "-") {
+        return Err(anyhow!("--verify reads your answer from stdin, so it can't send stdin"));
+    }
     // Fail early if there are problems generating file handles
-    let handles = FileHandle::get_file_handles(file_paths).await?;
+    let handles = FileHandle::get_file_handles(file_paths, symlinks).await?;
 
     // Establish connection to server
-    let socket = TcpStream::connect(relay)
-        .await
-        .with_context(|| format!("Failed to connect to relay at {}", relay))?;
-    socket.set_nodelay(true)?;
-
-    let pw = validate_generate_pw(password.clone())?;
+    let socket = connect_to_relay(relay).await?;
 
     // Display receive command for the user
     let relay_flag = if relay == DEFAULT_RELAY {
@@ -33,153 +50,570 @@ pub async fn send(file_paths: &Vec<PathBuf>, password: &Option<String>, relay: &
     } else {
         format!(" --relay {}", relay)
     };
-    println!(
+    eprintln!(
         "\n  {}\n  {}{}\n",
         "On the other computer, run:".dimmed(),
         format!("ruck-relay receive {}", pw).green().bold(),
         relay_flag.green()
     );
     debug!(password = %pw, relay = %relay, "Waiting for receiver");
-    let (handshake, s1) = Handshake::from_password(&pw)?;
-    // Complete handshake, returning key used for encryption
-    let (socket, key) = handshake
-        .negotiate(socket, s1)
-        .await
-        .map_err(|e| {
-            error!("Connection lost during handshake. The server may have rejected the connection (at capacity) or peer matching timed out.");
-            e
-        })?;
+    let (mut connection, key, cipher) = establish_session(socket, pw, Role::Sender, verify).await?;
+
+    if let Some(text) = text {
+        connection
+            .send_msg(Message::Text(TextPayload { text: text.clone() }))
+            .await?;
+        connection.close().await?;
+        eprintln!("{}", "Text sent.".green());
+        return Ok(());
+    }
 
-    let mut connection = Connection::new(socket, key);
     // Offer files, wait for requested file response
-    let requested_chunks = offer_files(&mut connection, &handles).await?;
+    let request = offer_files(&mut connection, &handles, streams).await?;
+    if request.streams == 0 || request.streams > streams {
+        return Err(anyhow!("Receiver asked for {} connections", request.streams));
+    }
+    let requested_chunks = request.chunks;
+
+    let mut signatures = receive_signatures(&mut connection, &requested_chunks).await?;
 
     // Upload negotiated files
-    let std_file_handles = FileHandle::to_stds(handles, requested_chunks).await;
-    connection.upload_files(std_file_handles).await?;
-    println!("{}", "Transfer complete.".green());
+    let mut std_file_handles = FileHandle::to_stds(handles, requested_chunks).await;
+    for handle in std_file_handles.iter_mut() {
+        handle.delta = signatures.remove(&handle.id);
+    }
+    let connections =
+        open_streams(connection, relay, &key, Role::Sender, cipher, request.streams).await?;
+    Connection::upload_files(connections, std_file_handles).await?;
+    eprintln!("{}", "Transfer complete.".green());
 
     // Exit
     Ok(())
 }
 
-pub async fn receive(password: &String, relay: &str) -> Result<()> {
+pub struct ReceiveOptions {
+    // Write accepted files to stdout instead of `out_dir`
+    pub to_stdout: bool,
+    pub out_dir: PathBuf,
+    pub policy: CollisionPolicy,
+    // Apply the sender's permissions and modification times
+    pub preserve_metadata: bool,
+    // Ask for the changes to files that already exist instead of the whole file
+    pub delta: bool,
+    // Wait for the user to confirm the verification code before going on
+    pub verify: bool,
+    // Accept every offered file without asking
+    pub accept_all: bool,
+}
+
+pub async fn receive(password: &str, relay: &str, options: &ReceiveOptions) -> Result<()> {
     // Establish connection to server
+    let socket = connect_to_relay(relay).await?;
+    let (mut connection, key, cipher) =
+        establish_session(socket, password, Role::Receiver, options.verify).await?;
+
+    // Wait for offered files or text
+    let offer = match connection.await_msg().await? {
+        Message::FileOffer(file_offer_payload) => file_offer_payload,
+        Message::Text(text_payload) => {
+            print_received_text(&text_payload.text);
+            return Ok(());
+        }
+        _ => return Err(anyhow!("Expecting file offer or text message")),
+    };
+    // Respond with desired files
+    let (std_file_handles, streams) =
+        request_specific_files(&mut connection, offer, options).await?;
+    // Download them
+    let connections = open_streams(connection, relay, &key, Role::Receiver, cipher, streams).await?;
+    Connection::download_files(connections, std_file_handles).await?;
+    Ok(())
+}
+
+async fn connect_to_relay(relay: &str) -> Result<TcpStream> {
     let socket = TcpStream::connect(relay)
         .await
         .with_context(|| format!("Failed to connect to relay at {}", relay))?;
     socket.set_nodelay(true)?;
+    Ok(socket)
+}
+
+// Returns the session's connection, key and cipher
+async fn establish_session(
+    socket: TcpStream,
+    password: &str,
+    role: Role,
+    verify: bool,
+) -> Result<(Connection, Vec<u8>, Cipher)> {
     let (handshake, s1) = Handshake::from_password(password)?;
     // Complete handshake, returning key used for encryption
-    let (socket, key) = handshake
+    let (socket, key, transcript) = handshake
         .negotiate(socket, s1)
         .await
-        .map_err(|e| {
+        .inspect_err(|_| {
             error!("Connection lost during handshake. The server may have rejected the connection (at capacity) or peer matching timed out.");
-            e
         })?;
-    let mut connection = Connection::new(socket, key);
-    // Wait for offered files, respond with desired files
-    let std_file_handles = request_specific_files(&mut connection).await?;
-    // Download them
-    connection.download_files(std_file_handles).await?;
-    return Ok(());
+    let socket = Handshake::check_version(socket).await?;
+    let socket = Handshake::confirm_key(socket, &key, &transcript, role).await?;
+    let code = Handshake::verification_code(&key, &transcript, role);
+    let (socket, cipher, key) = Handshake::negotiate_cipher(socket, &key).await?;
+    print_session_info(cipher, &code);
+    if verify {
+        confirm_verification_code().await?;
+    }
+    let keys = ConnectionKeys::derive(&key, role, 0);
+    Ok((Connection::new(socket, keys, cipher), key, cipher))
+}
+
+// Adds connections to the session until there are `streams` of them
+async fn open_streams(
+    connection: Connection,
+    relay: &str,
+    key: &[u8],
+    role: Role,
+    cipher: Cipher,
+    streams: u16,
+) -> Result<Vec<Connection>> {
+    let mut connections = vec![connection];
+    for index in 1..streams {
+        let socket = connect_to_relay(relay).await?;
+        let (handshake, expected) = Handshake::for_stream(key, role, index);
+        let socket = handshake.negotiate_stream(socket, expected, index).await?;
+        let keys = ConnectionKeys::derive(key, role, index);
+        connections.push(Connection::new(socket, keys, cipher));
+    }
+    debug!(streams, "Session connections established");
+    Ok(connections)
+}
+
+// The text itself goes to stdout so it can be piped, everything else to stderr
+fn print_received_text(text: &str) {
+    if std::io::stdout().is_terminal() {
+        eprintln!("{}", "Received text:".green());
+    }
+    println!("{}", text);
 }
 
 pub async fn offer_files(
     conn: &mut Connection,
-    file_handles: &Vec<FileHandle>,
-) -> Result<Vec<ChunkHeader>> {
+    file_handles: &[FileHandle],
+    streams: u16,
+) -> Result<FileRequestPayload> {
     // Collect file offer
-    let mut files = vec![];
-    for handle in file_handles {
-        files.push(handle.to_file_offer()?);
-    }
-    let msg = Message::FileOffer(FileOfferPayload { files });
+    let files = file_handles.iter().map(FileHandle::to_file_offer).collect();
+    let msg = Message::FileOffer(FileOfferPayload { files, streams });
     // Send file offer
     conn.send_msg(msg).await?;
     // Wait for reply
-    let reply = conn.await_msg().await?;
-    // Return requested chunks
-    match reply {
-        Message::FileRequest(file_request_payload) => Ok(file_request_payload.chunks),
-        _ => Err(anyhow!("Expecting file request message back")),
+    loop {
+        match conn.await_msg().await? {
+            // The receiver has copies of some files and checks them first
+            Message::DigestRequest(payload) => {
+                let mut digests = Vec::new();
+                for id in payload.files {
+                    let handle = file_handles
+                        .iter()
+                        .find(|handle| handle.id == id)
+                        .ok_or_else(|| anyhow!("Receiver asked for the digest of file {}", id))?;
+                    digests.push(handle.digest().await?);
+                }
+                conn.send_msg(Message::Digests(DigestsPayload { digests })).await?;
+            }
+            // Return requested chunks
+            Message::FileRequest(file_request_payload) => return Ok(file_request_payload),
+            _ => return Err(anyhow!("Expecting file request message back")),
+        }
     }
 }
 
-pub async fn request_specific_files(conn: &mut Connection) -> Result<Vec<StdFileHandle>> {
-    // Wait for offer message
-    let offer_message = conn.await_msg().await?;
-    let offered_files: Vec<FileOffer> = match offer_message {
-        Message::FileOffer(file_offer_payload) => file_offer_payload.files,
-        _ => return Err(anyhow!("Expecting file offer message")),
-    };
+// Collects the signatures the receiver sends for each file it wants as a delta
+async fn receive_signatures(
+    conn: &mut Connection,
+    requested_chunks: &[ChunkRequest],
+) -> Result<HashMap<FileId, Signatures>> {
+    let mut signatures = HashMap::new();
+    for _ in requested_chunks.iter().filter(|request| request.delta) {
+        match conn.await_msg().await? {
+            Message::FileSignatures(payload) => {
+                // The encoder slides a window of this size along the data
+                if payload.signatures.block_size < DELTA_MIN_BLOCK_SIZE {
+                    return Err(anyhow!(
+                        "Invalid delta block size {}",
+                        payload.signatures.block_size
+                    ));
+                }
+                signatures.insert(payload.file_id, payload.signatures);
+            }
+            _ => return Err(anyhow!("Expecting file signatures message")),
+        }
+    }
+    Ok(signatures)
+}
+
+pub async fn request_specific_files(
+    conn: &mut Connection,
+    offer: FileOfferPayload,
+    options: &ReceiveOptions,
+) -> Result<(Vec<StdFileHandle>, u16)> {
+    // Reject hostile paths before showing them to the user
+    let mut offered_files = sanitize_offers(offer.files)?;
+    if !options.preserve_metadata {
+        for offer in offered_files.iter_mut() {
+            offer.metadata = FileMetadata::default();
+        }
+    }
+    if !options.to_stdout {
+        offered_files = skip_up_to_date(conn, offered_files, &options.out_dir).await?;
+    }
     // Prompt user for confirmation of files
-    let desired_files = prompt_user_for_file_confirmation(offered_files).await;
-    let std_file_handles = create_or_find_files(desired_files).await?;
+    let desired_files =
+        prompt_user_for_file_confirmation(offered_files, options.accept_all).await?;
+    let std_file_handles = if options.to_stdout {
+        stdout_handles(desired_files)
+    } else {
+        create_or_find_files(desired_files, options).await?
+    };
+    // Output to stdout has to arrive in order, over a single connection
+    let streams = if options.to_stdout {
+        1
+    } else {
+        let files = u16::try_from(std_file_handles.len()).unwrap_or(u16::MAX);
+        offer.streams.min(MAX_STREAMS).min(files).max(1)
+    };
     let file_request_msg = Message::FileRequest(FileRequestPayload {
         chunks: std_file_handles
             .iter()
-            .map(|file| ChunkHeader {
-                id: file.id,
-                start: file.start,
-            })
+            .map(StdFileHandle::to_chunk_request)
             .collect(),
+        streams,
     });
     conn.send_msg(file_request_msg).await?;
-    Ok(std_file_handles)
+    for handle in &std_file_handles {
+        if let Some(signatures) = &handle.delta {
+            let msg = Message::FileSignatures(FileSignaturesPayload {
+                file_id: handle.id,
+                signatures: signatures.clone(),
+            });
+            conn.send_msg(msg).await?;
+        }
+    }
+    Ok((std_file_handles, streams))
 }
 
-pub async fn create_or_find_files(desired_files: Vec<FileOffer>) -> Result<Vec<StdFileHandle>> {
-    let mut v = Vec::new();
-    for desired_file in desired_files {
-        let filename = desired_file.path;
-        let can_resume = desired_file.compression == CompressionType::None;
+// Leaves out files whose local copy already matches the sender's digest, and
+// links that already point at the offered target
+async fn skip_up_to_date(
+    conn: &mut Connection,
+    offered_files: Vec<FileOffer>,
+    out_dir: &Path,
+) -> Result<Vec<FileOffer>> {
+    // Offers in order, with the local copy to compare if it has the offered size
+    let mut entries = Vec::new();
+    for offer in offered_files {
+        let path = out_dir.join(&offer.path);
+        // Never look at files outside the output directory, asking for their
+        // digests would tell the sender what is there
+        if ensure_parent_within(out_dir, &path).is_err() {
+            entries.push((offer, None));
+            continue;
+        }
+        let md = tokio::fs::symlink_metadata(&path).await;
+        match (&offer.kind, md) {
+            (EntryKind::Symlink(target), Ok(md))
+                if md.is_symlink() && tokio::fs::read_link(&path).await? == Path::new(target) =>
+            {
+                eprintln!("{} {} (already up to date)", "Skipping".green(), offer.path);
+            }
+            (EntryKind::File, Ok(md)) if md.is_file() && offer.size == Some(md.len()) => {
+                entries.push((offer, Some(path)));
+            }
+            _ => entries.push((offer, None)),
+        }
+    }
 
-        enum Status {
-            New,
-            Overwrite,
-            Resume(u64),
+    // Only files that could possibly match are hashed, on both sides
+    let files: Vec<FileId> = entries
+        .iter()
+        .filter(|(_, path)| path.is_some())
+        .map(|(offer, _)| offer.id)
+        .collect();
+    if files.is_empty() {
+        return Ok(entries.into_iter().map(|(offer, _)| offer).collect());
+    }
+    conn.send_msg(Message::DigestRequest(DigestRequestPayload {
+        files: files.clone(),
+    }))
+    .await?;
+    let digests: HashMap<FileId, FileDigest> = match conn.await_msg().await? {
+        Message::Digests(payload) if payload.digests.len() == files.len() => {
+            files.into_iter().zip(payload.digests).collect()
+        }
+        _ => return Err(anyhow!("Expecting file digests message")),
+    };
+
+    let mut remaining = Vec::new();
+    for (offer, path) in entries {
+        if let (Some(path), Some(size)) = (path, offer.size) {
+            if finalize(hash_file_prefix(&path, size).await?) == digests[&offer.id] {
+                eprintln!("{} {} (already up to date)", "Skipping".green(), offer.path);
+                continue;
+            }
         }
+        remaining.push(offer);
+    }
+    Ok(remaining)
+}
 
-        let (file, start, status) = match OpenOptions::new().write(true).open(&filename).await {
-            Ok(file) => {
-                let metadata = file.metadata().await?;
-                let existing_len = metadata.len();
+pub async fn create_or_find_files(
+    desired_files: Vec<FileOffer>,
+    options: &ReceiveOptions,
+) -> Result<Vec<StdFileHandle>> {
+    let out_dir = options.out_dir.as_path();
+    let policy = options.policy;
+    tokio::fs::create_dir_all(out_dir).await?;
+    if policy == CollisionPolicy::Fail {
+        // Check everything up front so nothing is written if any file exists
+        let mut offered = HashSet::new();
+        for desired_file in &desired_files {
+            let path = out_dir.join(&desired_file.path);
+            if desired_file.kind != EntryKind::Directory
+                && (!offered.insert(&desired_file.path)
+                    || tokio::fs::symlink_metadata(&path).await.is_ok())
+            {
+                return Err(anyhow!(
+                    "{} already exists. Use --collision to choose how to handle existing files",
+                    path.display()
+                ));
+            }
+        }
+    }
 
-                if can_resume && existing_len > 0 && existing_len < desired_file.size {
-                    (file, existing_len, Status::Resume(existing_len))
-                } else {
-                    file.set_len(0).await?;
-                    (file, 0, Status::Overwrite)
+    // Names given to earlier files of this batch, which may not be on disk yet
+    let mut claimed = HashSet::new();
+    let mut v = Vec::new();
+    for desired_file in desired_files {
+        match &desired_file.kind {
+            EntryKind::Symlink(target) => {
+                create_symlink(out_dir, &desired_file.path, target, policy, &mut claimed).await?;
+                continue;
+            }
+            EntryKind::Directory => {
+                let path = out_dir.join(&desired_file.path);
+                ensure_parent_within(out_dir, &path)?;
+                // Whatever is there already, there is nothing to write into it
+                if tokio::fs::symlink_metadata(&path).await.is_err() {
+                    tokio::fs::create_dir_all(&path).await?;
                 }
+                continue;
             }
-            Err(_) => {
-                let file = File::create(&filename).await?;
-                (file, 0, Status::New)
+            EntryKind::File => {}
+        }
+        let mut filename = desired_file.path;
+        let mut path = out_dir.join(&filename);
+        // A link at the destination itself is just another existing entry, it
+        // is replaced rather than written through
+        ensure_parent_within(out_dir, &path)?;
+        // Rebuild the sender's directory layout for nested files
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        let mut display = filename.clone();
+        let mut notes = Vec::new();
+        let mut existing = false;
+        let duplicate = claimed.contains(&filename);
+        let md = tokio::fs::symlink_metadata(&path).await;
+        // A local copy that delta transfers can reuse blocks from, even when
+        // the new one is saved under another name
+        let basis = match &md {
+            Ok(md) if md.is_file() && !duplicate => Some(path.clone()),
+            _ => None,
+        };
+        if duplicate || md.is_ok() {
+            match policy {
+                CollisionPolicy::Skip => {
+                    eprintln!("{} {} (exists, skipped)", "Skipping".yellow(), filename);
+                    continue;
+                }
+                CollisionPolicy::Rename => {
+                    filename = unused_name(out_dir, &filename, &claimed).await;
+                    path = out_dir.join(&filename);
+                    display = format!("{} as {}", display, filename);
+                    notes.push("exists, renamed".to_string());
+                }
+                _ if duplicate => {
+                    return Err(anyhow!(
+                        "Sender offered {} more than once. Use --collision rename or skip to receive it",
+                        filename
+                    ));
+                }
+                _ => existing = true,
+            }
+        }
+        claimed.insert(filename.clone());
+
+        // Data is written to a partial file next to the destination, and only
+        // moved into place once it has been verified
+        let part_path = append_suffix(&path, PART_SUFFIX);
+        ensure_within(out_dir, &part_path)?;
+        let part_len = tokio::fs::metadata(&part_path).await.ok().map(|md| md.len());
+        let start = match part_len {
+            Some(len)
+                // Partial files are our own, so they are resumed unless told otherwise
+                if matches!(policy, CollisionPolicy::Resume | CollisionPolicy::Rename)
+                    && len > 0
+                    && desired_file.size.is_some_and(|size| len < size) =>
+            {
+                len
             }
+            _ => 0,
         };
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(start == 0)
+            .open(&part_path)
+            .await?;
+        // Only the changed parts of an existing copy are transferred
+        let delta = match &basis {
+            Some(basis) if options.delta && start == 0 => Some(Signatures::from_file(basis).await?),
+            _ => None,
+        };
+        if start > 0 {
+            notes.push(format!("resuming from {}", to_size_string(start)));
+        } else if delta.is_some() && existing {
+            notes.push("exists, updating changed blocks".to_string());
+        } else if delta.is_some() {
+            notes.push("reusing unchanged blocks".to_string());
+        } else if existing {
+            notes.push("exists, overwriting".to_string());
+        }
 
-        match status {
-            Status::New => println!("{} {}", "Downloading".cyan(), filename),
-            Status::Overwrite => println!("{} {} (overwriting)", "Downloading".yellow(), filename),
-            Status::Resume(from) => println!(
-                "{} {} (resuming from {})",
+        if notes.is_empty() {
+            eprintln!("{} {}", "Downloading".cyan(), display);
+        } else {
+            eprintln!(
+                "{} {} ({})",
                 "Downloading".yellow(),
-                filename,
-                crate::file::to_size_string(from)
-            ),
+                display,
+                notes.join(", ")
+            );
         }
 
-        let std_file_handle = StdFileHandle::new(
+        // Close the file until its transfer begins
+        drop(file);
+        // Hash the existing prefix so the sender can confirm it before resuming
+        let prefix = hash_file_prefix(&part_path, start).await?;
+        let mut std_file_handle = StdFileHandle::new(
             desired_file.id,
             filename,
-            file,
+            Some(path),
             start,
             desired_file.size,
-        )
-        .await?;
+            prefix,
+            desired_file.metadata,
+        );
+        if delta.is_some() {
+            std_file_handle.basis = basis;
+        }
+        std_file_handle.delta = delta;
         v.push(std_file_handle)
     }
-    return Ok(v);
+    Ok(v)
+}
+
+// Links carry no data, so they are created right away instead of being requested
+async fn create_symlink(
+    out_dir: &Path,
+    filename: &str,
+    target: &str,
+    policy: CollisionPolicy,
+    claimed: &mut HashSet<String>,
+) -> Result<()> {
+    let mut filename = filename.to_string();
+    let mut path = out_dir.join(&filename);
+    ensure_parent_within(out_dir, &path)?;
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    let duplicate = claimed.contains(&filename);
+    let md = tokio::fs::symlink_metadata(&path).await;
+    if duplicate || md.is_ok() {
+        match policy {
+            CollisionPolicy::Skip => {
+                eprintln!("{} {} (exists, skipped)", "Skipping".yellow(), filename);
+                return Ok(());
+            }
+            CollisionPolicy::Rename => {
+                filename = unused_name(out_dir, &filename, claimed).await;
+                path = out_dir.join(&filename);
+            }
+            _ if duplicate => {
+                return Err(anyhow!(
+                    "Sender offered {} more than once. Use --collision rename or skip to receive it",
+                    filename
+                ));
+            }
+            _ if md.as_ref().is_ok_and(|md| md.is_dir()) => {
+                return Err(anyhow!("Can't replace directory {:?} with a link", path));
+            }
+            _ => tokio::fs::remove_file(&path).await?,
+        }
+    }
+    claimed.insert(filename.clone());
+    #[cfg(unix)]
+    {
+        ensure_link_within(out_dir, &path, target)?;
+        tokio::fs::symlink(target, &path).await?;
+        eprintln!("{} {} -> {}", "Linking".cyan(), filename, target);
+    }
+    #[cfg(not(unix))]
+    tracing::warn!(
+        "Symlinks are not supported on this platform, skipping {} -> {}",
+        filename,
+        target
+    );
+    Ok(())
+}
+
+// Finds the first free name of the form `file (1).txt` next to `filename`,
+// that isn't on disk or `claimed` by another file of the batch
+async fn unused_name(out_dir: &Path, filename: &str, claimed: &HashSet<String>) -> String {
+    let (base, ext) = match filename.rsplit_once('.') {
+        Some((base, ext)) if !base.is_empty() && !base.ends_with('/') && !ext.contains('/') => {
+            (base, format!(".{}", ext))
+        }
+        _ => (filename, String::new()),
+    };
+    let mut n = 1;
+    loop {
+        let candidate = format!("{} ({}){}", base, n, ext);
+        if !claimed.contains(&candidate)
+            && tokio::fs::symlink_metadata(out_dir.join(&candidate)).await.is_err()
+        {
+            return candidate;
+        }
+        n += 1;
+    }
+}
+
+// Accepted files are written to stdout back to back, in the order they were offered
+pub fn stdout_handles(desired_files: Vec<FileOffer>) -> Vec<StdFileHandle> {
+    desired_files
+        .into_iter()
+        // Links and directories have no data to write
+        .filter(|desired_file| desired_file.kind == EntryKind::File)
+        .map(|desired_file| {
+            eprintln!("{} {} (to stdout)", "Downloading".cyan(), desired_file.path);
+            StdFileHandle::new(
+                desired_file.id,
+                desired_file.path,
+                None,
+                0,
+                desired_file.size,
+                Hasher::default(),
+                FileMetadata::default(),
+            )
+        })
+        .collect()
 }
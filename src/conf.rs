@@ -1,13 +1,31 @@
 pub const ID_SIZE: usize = 32; // Blake256 of password
 pub const HANDSHAKE_MSG_SIZE: usize = 33; // generated by Spake2
+pub const VERIFICATION_CODE_LEN: usize = 5; // emoji shown by --verify, 6 bits each
+pub const CONFIRMATION_TAG_SIZE: usize = 32; // HMAC-SHA256 of the handshake transcript
 pub const BUFFER_SIZE: usize = 1024 * 1024; // chunk size for files sent over wire (1MB)
-pub const NONCE_SIZE: usize = 96 / 8; // used for every encrypted message
+pub const HASH_BLOCK_SIZE: u64 = 1024 * 1024; // file digests are taken over digests of blocks this size
 pub const PASSWORD_LEN: usize = 16; // generated password length (~95 bits entropy with base62)
+pub const PASSWORD_ENV: &str = "RUCK_PASSWORD"; // read when no password is passed as an argument
+pub const CODE_CHANNEL_DIGITS: usize = 6; // digits in the channel prefix of a word code
+pub const CODE_WORDS: usize = 3; // words after the channel prefix of a word code
 pub const ZSTD_COMPRESSION_LEVEL: i32 = 3; // zstd compression level for file transfers
+pub const MAX_NAME_LEN: usize = 255; // longest file name accepted from a sender
+pub const MAX_PATH_LEN: usize = 4096; // longest relative path accepted from a sender
+pub const PART_SUFFIX: &str = ".ruck-part"; // appended to files while they are downloading
+pub const QUARANTINE_SUFFIX: &str = ".ruck-corrupt"; // appended to files failing verification
+pub const PROTOCOL_MAGIC: &[u8; 2] = b"RU"; // prefix of every serialized message
+pub const DELTA_MIN_BLOCK_SIZE: u64 = 4096; // smallest block compared in delta transfers
+pub const DELTA_MAX_BLOCKS: u64 = 256 * 1024; // keeps signature messages well under the frame limit
+pub const REKEY_INTERVAL: u64 = 1 << 20; // frames sent under one key before the next is derived
+pub const PIPELINE_DEPTH: usize = 4; // chunks in flight between transfer stages
+pub const MAX_STREAMS: u16 = 16; // most connections a transfer can be spread across
+pub const PROTOCOL_VERSION: u16 = 2; // bump on any change to the message format
 
 // Network defaults
 pub const DEFAULT_RELAY: &str = "174.138.70.74:8080";
 pub const DEFAULT_BIND: &str = "0.0.0.0:8080";
 pub const DEFAULT_MAX_CLIENTS: usize = 1000;
 pub const DEFAULT_PEER_TIMEOUT_SECS: u64 = 60; // 1 minute
+pub const DEFAULT_HANDSHAKE_TIMEOUT_SECS: u64 = 10; // to send the handshake after connecting
+pub const DEFAULT_MAX_HANDSHAKES: usize = 256; // connections still waiting for their handshake
 pub const BROADCAST_CHANNEL_CAPACITY: usize = 1000; // buffer for peer connection notifications
@@ -1,52 +1,115 @@
-use crate::conf::{BUFFER_SIZE, ZSTD_COMPRESSION_LEVEL};
-use crate::crypto::Crypt;
-use crate::file::{should_compress, ChunkHeader, CompressionType, StdFileHandle};
-use crate::message::{FileTransferPayload, FileTransferStartPayload, Message, MessageStream};
+use crate::conf::{BUFFER_SIZE, PIPELINE_DEPTH, QUARANTINE_SUFFIX, ZSTD_COMPRESSION_LEVEL};
+use crate::crypto::{Cipher, CipherState, ConnectionKeys};
+use crate::delta::{apply_delta, DeltaEncoder, DeltaInstruction};
+use crate::file::{
+    append_suffix, should_compress, ChunkHeader, CompressionType, FileId, Input, StdFileHandle,
+};
+use crate::hash::{finalize, to_hex, update_zeros, FileDigest, Hasher};
+use crate::message::{
+    FileDeltaPayload, FileHolePayload, FileTransferCompletePayload, FileTransferPayload,
+    FileTransferStartPayload, Message, MessageStream,
+};
+use crate::ui::{print_status, transfer_progress_bar};
 
 use anyhow::{anyhow, Result};
-use async_compression::tokio::bufread::ZstdEncoder;
-use async_compression::tokio::write::ZstdDecoder;
-use bytes::{Bytes, BytesMut};
+use bytes::Bytes;
 use colored::Colorize;
+use futures::future::try_join_all;
+use futures::stream::{SplitSink, SplitStream};
 use futures::{SinkExt, StreamExt};
-use indicatif::{ProgressBar, ProgressStyle};
+use indicatif::ProgressBar;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
 use std::time::Instant;
-use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
+use tokio::sync::{mpsc, Mutex};
+use tokio::task::JoinHandle;
 
+// Messages are serialized and encrypted on the blocking pool, and written to
+// the socket by their own task. Incoming frames are read and decrypted the same
+// way. The channels between the stages are bounded, so only a few chunks are
+// held in memory at a time.
 pub struct Connection {
-    ms: MessageStream,
-    crypt: Crypt,
+    outbound_cipher: CipherState,
+    // Encryption jobs, in the order their frames have to be written
+    outbound: Option<mpsc::Sender<JoinHandle<Result<Bytes>>>>,
+    writer: Option<JoinHandle<Result<()>>>,
+    // Decryption jobs, in the order their frames arrived
+    inbound: mpsc::Receiver<JoinHandle<Result<Message>>>,
+    reader: JoinHandle<()>,
 }
 
 impl Connection {
-    pub fn new(socket: TcpStream, key: Vec<u8>) -> Self {
-        let ms = Message::to_stream(socket);
-        let crypt = Crypt::new(&key);
-        Connection { ms, crypt }
+    pub fn new(socket: TcpStream, keys: ConnectionKeys, cipher: Cipher) -> Self {
+        let inbound_cipher = CipherState::new(cipher, keys.inbound);
+        let (sink, stream) = Message::to_stream(socket).split();
+        let (outbound, outbound_rx) = mpsc::channel(PIPELINE_DEPTH);
+        let (inbound_tx, inbound) = mpsc::channel(PIPELINE_DEPTH);
+        Connection {
+            outbound_cipher: CipherState::new(cipher, keys.outbound),
+            outbound: Some(outbound),
+            writer: Some(tokio::spawn(write_frames(sink, outbound_rx))),
+            inbound,
+            reader: tokio::spawn(read_frames(stream, inbound_tx, inbound_cipher)),
+        }
     }
 
-    pub async fn send_bytes(&mut self, bytes: Bytes) -> Result<()> {
-        match self.ms.send(bytes).await {
-            Ok(_) => Ok(()),
-            Err(e) => Err(anyhow!(e.to_string())),
+    pub async fn send_msg(&mut self, msg: Message) -> Result<()> {
+        self.send_with(move || Ok(msg)).await
+    }
+
+    // Queues the message built by `build`, which runs on the blocking pool
+    // along with serialization and encryption
+    pub async fn send_with<F>(&mut self, build: F) -> Result<()>
+    where
+        F: FnOnce() -> Result<Message> + Send + 'static,
+    {
+        let outbound = self
+            .outbound
+            .as_ref()
+            .ok_or_else(|| anyhow!("Connection closed"))?;
+        let (crypt, nonce) = self.outbound_cipher.next();
+        let job =
+            tokio::task::spawn_blocking(move || crypt.encrypt(nonce, build()?.serialize()?));
+        if outbound.send(job).await.is_err() {
+            return Err(self.writer_error().await);
         }
+        Ok(())
     }
 
-    pub async fn send_msg(&mut self, msg: Message) -> Result<()> {
-        let msg = msg.serialize()?;
-        let encrypted = self.crypt.encrypt(msg)?;
-        self.send_bytes(encrypted).await
+    // Waits until every queued message has been written
+    pub async fn close(mut self) -> Result<()> {
+        self.outbound = None;
+        match self.writer.take() {
+            Some(writer) => writer.await?,
+            None => Ok(()),
+        }
+    }
+
+    async fn writer_error(&mut self) -> anyhow::Error {
+        match self.writer.take() {
+            Some(writer) => match writer.await {
+                Ok(Err(e)) => e,
+                Ok(Ok(())) => anyhow!("Connection closed"),
+                Err(e) => e.into(),
+            },
+            None => anyhow!("Connection closed"),
+        }
     }
 
     pub async fn await_msg(&mut self) -> Result<Message> {
-        match self.ms.next().await {
-            Some(Ok(msg)) => {
-                let decrypted_bytes = self.crypt.decrypt(msg.freeze())?;
-                Message::deserialize(decrypted_bytes)
-            }
-            Some(Err(e)) => Err(anyhow!(e.to_string())),
-            None => Err(anyhow!("Error awaiting msg")),
+        self.next_msg()
+            .await?
+            .ok_or_else(|| anyhow!("Error awaiting msg"))
+    }
+
+    // Returns None if the peer closed the connection
+    pub async fn next_msg(&mut self) -> Result<Option<Message>> {
+        match self.inbound.recv().await {
+            Some(job) => Ok(Some(job.await??)),
+            None => Ok(None),
         }
     }
 
@@ -65,172 +128,550 @@ impl Connection {
         let start_msg = Message::FileTransferStart(FileTransferStartPayload {
             file_id: handle.id,
             compression: compression_type,
+            start: handle.start,
         });
         self.send_msg(start_msg).await?;
 
-        // Set up file reader (already seeked to handle.start)
-        let file = tokio::fs::File::from_std(handle.file);
-        let reader = BufReader::new(file);
+        // Start from the verified prefix, so the digest covers the whole file
+        let mut hasher = handle.prefix.clone();
+
+        // Set up file reader, seeked to handle.start
+        let mut reader = handle.open_read().await?;
 
         // Set up progress bar for total file size, starting at resume position
-        let pb = ProgressBar::new(handle.size);
-        pb.set_style(
-            ProgressStyle::default_bar()
-                .template("{spinner:.green} [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta})")
-                .unwrap()
-                .progress_chars("#>-"),
-        );
+        let pb = transfer_progress_bar(handle.size, handle.start);
         pb.set_message(handle.name.clone());
-        pb.set_position(handle.start);
 
-        let mut buffer = vec![0u8; BUFFER_SIZE];
-        let mut bytes_sent: u64 = 0;
-
-        let mut reader: std::pin::Pin<Box<dyn tokio::io::AsyncRead + Send>> = if use_compression {
-            Box::pin(ZstdEncoder::with_quality(reader, async_compression::Level::Precise(ZSTD_COMPRESSION_LEVEL)))
+        let bytes_sent = if handle.delta.is_some() {
+            self.send_delta(&handle, &mut reader, &mut hasher, &pb, use_compression)
+                .await?
         } else {
-            Box::pin(reader)
+            self.send_regions(&handle, &mut reader, &mut hasher, &pb, use_compression)
+                .await?
         };
 
-        loop {
-            let n = reader.read(&mut buffer).await?;
-            if n == 0 {
-                break;
-            }
-
-            bytes_sent += n as u64;
-            pb.set_position((handle.start + bytes_sent).min(handle.size));
-
-            let msg = Message::FileTransfer(FileTransferPayload {
-                chunk_header: ChunkHeader {
-                    id: handle.id,
-                    start: handle.start,
-                },
-                chunk: BytesMut::from(&buffer[..n]).freeze(),
-            });
-            self.send_msg(msg).await?;
-        }
-
         pb.finish_and_clear();
 
         // Send FileTransferComplete message
-        self.send_msg(Message::FileTransferComplete).await?;
+        let complete_msg = Message::FileTransferComplete(FileTransferCompletePayload {
+            file_id: handle.id,
+            digest: finalize(hasher),
+        });
+        self.send_msg(complete_msg).await?;
 
         let elapsed = before.elapsed();
         let mb_sent = bytes_sent as f64 / 1_048_576.0;
         let elapsed_secs = elapsed.as_secs_f64().max(0.001);
-        println!(
+        print_status(format!(
             "{} {} ({:.1} MB, {:.1} MB/s)",
             "Sent".green(),
             handle.name,
             mb_sent,
             mb_sent / elapsed_secs
-        );
+        ));
         Ok(())
     }
 
-    pub async fn upload_files(mut self, handles: Vec<StdFileHandle>) -> Result<()> {
-        for handle in handles {
+    // Sends the file's data regions from `handle.start`, returning the number of bytes sent
+    async fn send_regions(
+        &mut self,
+        handle: &StdFileHandle,
+        reader: &mut Input,
+        hasher: &mut Hasher,
+        pb: &ProgressBar,
+        use_compression: bool,
+    ) -> Result<u64> {
+        let mut bytes_sent: u64 = 0;
+        let mut offset = handle.start;
+
+        // Only data regions are read and sent, holes are described by their length
+        let regions = handle.data_regions().await?;
+        let end = handle.size.unwrap_or(u64::MAX);
+        for region in regions.into_iter().chain(std::iter::once(end..end)) {
+            if region.start > offset {
+                let len = region.start - offset;
+                let msg = Message::FileHole(FileHolePayload {
+                    chunk_header: ChunkHeader {
+                        id: handle.id,
+                        start: offset,
+                    },
+                    len,
+                });
+                self.send_msg(msg).await?;
+                // Holes aren't sent, but their zeros are still part of the digest
+                update_zeros(hasher, len);
+                reader.skip(len).await?;
+                offset = region.start;
+                pb.set_position(offset);
+            }
+
+            let mut region_reader = (&mut *reader).take(region.end - offset);
+            loop {
+                let mut raw = vec![0u8; BUFFER_SIZE];
+                let n = read_chunk(&mut region_reader, &mut raw).await?;
+                if n == 0 {
+                    break;
+                }
+                raw.truncate(n);
+                hasher.update(&raw);
+
+                // Each chunk is compressed independently, so any chunk boundary
+                // is a valid place to resume from
+                let chunk_header = ChunkHeader {
+                    id: handle.id,
+                    start: offset,
+                };
+                self.send_with(move || {
+                    Ok(Message::FileTransfer(FileTransferPayload {
+                        chunk_header,
+                        chunk: compress_chunk(&raw, use_compression)?,
+                    }))
+                })
+                .await?;
+
+                bytes_sent += n as u64;
+                offset += n as u64;
+                pb.set_position(offset);
+            }
+            // The file ended early, let the receiver's size check catch it
+            if offset < region.end {
+                break;
+            }
+        }
+        Ok(bytes_sent)
+    }
+
+    // Sends the file as instructions against the receiver's existing copy,
+    // returning the number of new bytes sent
+    async fn send_delta(
+        &mut self,
+        handle: &StdFileHandle,
+        reader: &mut Input,
+        hasher: &mut Hasher,
+        pb: &ProgressBar,
+        use_compression: bool,
+    ) -> Result<u64> {
+        let signatures = handle
+            .delta
+            .clone()
+            .ok_or_else(|| anyhow!("No signatures for delta transfer"))?;
+        let mut encoder = DeltaEncoder::new(signatures);
+        let mut buffer = vec![0u8; BUFFER_SIZE];
+        let mut bytes_sent: u64 = 0;
+        let mut offset: u64 = 0;
+        loop {
+            let n = read_chunk(reader, &mut buffer).await?;
+            let instructions = if n == 0 {
+                encoder.finish()
+            } else {
+                hasher.update(&buffer[..n]);
+                offset += n as u64;
+                encoder.push(&buffer[..n])
+            };
+            for instruction in &instructions {
+                if let DeltaInstruction::Literal(data) = instruction {
+                    bytes_sent += data.len() as u64;
+                }
+            }
+            if !instructions.is_empty() {
+                let file_id = handle.id;
+                self.send_with(move || {
+                    let instructions = instructions
+                        .into_iter()
+                        .map(|instruction| match instruction {
+                            DeltaInstruction::Literal(data) => Ok(DeltaInstruction::Literal(
+                                compress_chunk(&data, use_compression)?,
+                            )),
+                            copy => Ok(copy),
+                        })
+                        .collect::<Result<_>>()?;
+                    Ok(Message::FileDelta(FileDeltaPayload {
+                        file_id,
+                        instructions,
+                    }))
+                })
+                .await?;
+            }
+            pb.set_position(offset);
+            if n == 0 {
+                break;
+            }
+        }
+        Ok(bytes_sent)
+    }
+
+    // Each connection takes the next file from the queue until it is empty
+    pub async fn upload_files(
+        connections: Vec<Connection>,
+        handles: Vec<StdFileHandle>,
+    ) -> Result<()> {
+        let queue = Arc::new(Mutex::new(VecDeque::from(handles)));
+        let tasks = connections.into_iter().map(|mut conn| {
+            let queue = queue.clone();
+            tokio::spawn(async move {
+                conn.upload_queued(queue).await?;
+                conn.close().await
+            })
+        });
+        try_join_all(tasks.map(|task| async { task.await? })).await?;
+        Ok(())
+    }
+
+    async fn upload_queued(&mut self, queue: Arc<Mutex<VecDeque<StdFileHandle>>>) -> Result<()> {
+        loop {
+            let Some(handle) = queue.lock().await.pop_front() else {
+                return Ok(());
+            };
             self.upload_file(handle).await?;
         }
+    }
+
+    // Files may arrive on any connection, in any order
+    pub async fn download_files(
+        connections: Vec<Connection>,
+        handles: Vec<StdFileHandle>,
+    ) -> Result<()> {
+        let pending: HashMap<FileId, StdFileHandle> =
+            handles.into_iter().map(|handle| (handle.id, handle)).collect();
+        let pending = Arc::new(Mutex::new(pending));
+        let tasks = connections.into_iter().map(|mut conn| {
+            let pending = pending.clone();
+            tokio::spawn(async move {
+                conn.download_pending(pending).await?;
+                conn.close().await
+            })
+        });
+        try_join_all(tasks.map(|task| async { task.await? })).await?;
+        if !pending.lock().await.is_empty() {
+            return Err(anyhow!("Connection closed before all files were received"));
+        }
         Ok(())
     }
 
-    pub async fn download_files(mut self, handles: Vec<StdFileHandle>) -> Result<()> {
-        for handle in handles {
-            self.download_file(handle).await?;
+    async fn download_pending(
+        &mut self,
+        pending: Arc<Mutex<HashMap<FileId, StdFileHandle>>>,
+    ) -> Result<()> {
+        // The sender hangs up once every file has been sent
+        while !pending.lock().await.is_empty() {
+            let payload = match self.next_msg().await? {
+                Some(Message::FileTransferStart(payload)) => payload,
+                Some(_) => return Err(anyhow!("Expected FileTransferStart message")),
+                None => return Ok(()),
+            };
+            let handle = pending
+                .lock()
+                .await
+                .remove(&payload.file_id)
+                .ok_or_else(|| anyhow!("Unexpected file ID {}", payload.file_id))?;
+            self.download_file(handle, payload).await?;
         }
         Ok(())
     }
 
-    pub async fn download_file(&mut self, handle: StdFileHandle) -> Result<()> {
+    pub async fn download_file(
+        &mut self,
+        mut handle: StdFileHandle,
+        start_payload: FileTransferStartPayload,
+    ) -> Result<()> {
         let before = Instant::now();
-
-        // Await FileTransferStart message
-        let start_msg = self.await_msg().await?;
-        let compression = match start_msg {
-            Message::FileTransferStart(payload) => {
-                if payload.file_id != handle.id {
-                    return Err(anyhow!(
-                        "File ID mismatch: expected {}, got {}",
-                        handle.id,
-                        payload.file_id
-                    ));
-                }
-                payload.compression
+        let FileTransferStartPayload {
+            compression, start, ..
+        } = start_payload;
+
+        // The sender rejected our partial copy, so start over
+        if start != handle.start {
+            if start != 0 {
+                return Err(anyhow!("Unexpected resume offset {} from sender", start));
             }
-            _ => return Err(anyhow!("Expected FileTransferStart message")),
-        };
+            print_status(format!(
+                "{} {} (local data does not match sender, restarting)",
+                "Downloading".yellow(),
+                handle.name
+            ));
+            handle.restart().await?;
+        }
 
         let use_compression = compression == CompressionType::Zstd;
 
         // Set up progress bar for total file size, starting at resume position
-        let pb = ProgressBar::new(handle.size);
-        pb.set_style(
-            ProgressStyle::default_bar()
-                .template("{spinner:.green} [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta})")
-                .unwrap()
-                .progress_chars("#>-"),
-        );
-        pb.set_position(handle.start);
-
-        let file = tokio::fs::File::from_std(handle.file);
-        let mut bytes_received: u64 = 0;
-
-        let mut writer: std::pin::Pin<Box<dyn tokio::io::AsyncWrite + Send>> = if use_compression {
-            Box::pin(ZstdDecoder::new(file))
-        } else {
-            Box::pin(file)
+        let pb = transfer_progress_bar(handle.size, handle.start);
+
+        // Chunks are decompressed on the blocking pool while earlier ones are
+        // being written
+        let (blocks, mut pending_blocks) = mpsc::channel(PIPELINE_DEPTH);
+        let receive = async {
+            let blocks = blocks;
+            loop {
+                let block = match self.await_msg().await? {
+                    Message::FileTransfer(payload) => {
+                        if payload.chunk_header.id != handle.id {
+                            return Err(anyhow!("File ID mismatch in chunk"));
+                        }
+                        let chunk = payload.chunk;
+                        Block::Data {
+                            start: payload.chunk_header.start,
+                            job: tokio::task::spawn_blocking(move || {
+                                decompress_chunk(chunk, use_compression)
+                            }),
+                        }
+                    }
+                    Message::FileHole(payload) => {
+                        if payload.chunk_header.id != handle.id {
+                            return Err(anyhow!("File ID mismatch in hole"));
+                        }
+                        Block::Hole {
+                            start: payload.chunk_header.start,
+                            len: payload.len,
+                        }
+                    }
+                    Message::FileDelta(payload) => {
+                        if payload.file_id != handle.id {
+                            return Err(anyhow!("File ID mismatch in delta"));
+                        }
+                        let instructions = payload.instructions;
+                        Block::Delta(tokio::task::spawn_blocking(move || {
+                            decompress_literals(instructions, use_compression)
+                        }))
+                    }
+                    Message::FileTransferComplete(payload) => {
+                        if payload.file_id != handle.id {
+                            return Err(anyhow!("File ID mismatch in transfer completion"));
+                        }
+                        return Ok(payload.digest);
+                    }
+                    _ => return Err(anyhow!("Unexpected message during transfer")),
+                };
+                blocks
+                    .send(block)
+                    .await
+                    .map_err(|_| anyhow!("Transfer stopped"))?;
+            }
         };
-
-        loop {
-            let msg = self.await_msg().await?;
-            match msg {
-                Message::FileTransfer(payload) => {
-                    if payload.chunk_header.id != handle.id {
-                        return Err(anyhow!("File ID mismatch in chunk"));
+        // A sender can't write past the size it offered
+        let past_end = |offset: u64, len: u64| {
+            handle
+                .size
+                .is_some_and(|size| size.checked_sub(offset).is_none_or(|left| len > left))
+        };
+        let write = async {
+            // Start from the resumed prefix, so the digest covers the whole file
+            let mut hasher = handle.prefix.clone();
+            let mut writer = handle.open_write().await?;
+            let mut bytes_received: u64 = 0;
+            let mut offset = handle.start;
+
+            // Delta transfers copy blocks out of the receiver's existing copy
+            let mut basis = match (&handle.delta, &handle.basis) {
+                (Some(signatures), Some(path)) => Some((signatures, File::open(path).await?)),
+                _ => None,
+            };
+
+            while let Some(block) = pending_blocks.recv().await {
+                match block {
+                    Block::Data { start, job } => {
+                        if start != offset {
+                            return Err(anyhow!("Chunk offset mismatch in transfer"));
+                        }
+                        let chunk = job.await??;
+                        if past_end(offset, chunk.len() as u64) {
+                            return Err(anyhow!("Chunk extends past the end of the file"));
+                        }
+                        hasher.update(&chunk);
+                        writer.write_all(&chunk).await?;
+                        bytes_received += chunk.len() as u64;
+                        offset += chunk.len() as u64;
+                    }
+                    Block::Hole { start, len } => {
+                        if start != offset {
+                            return Err(anyhow!("Hole offset mismatch in transfer"));
+                        }
+                        if past_end(offset, len) {
+                            return Err(anyhow!("Hole extends past the end of the file"));
+                        }
+                        update_zeros(&mut hasher, len);
+                        writer.write_hole(len).await?;
+                        offset += len;
+                    }
+                    Block::Delta(job) => {
+                        let Some((signatures, basis)) = basis.as_mut() else {
+                            return Err(anyhow!("Unexpected delta for {}", handle.name));
+                        };
+                        let (written, literal) =
+                            apply_delta(job.await??, signatures, basis, &mut writer, &mut hasher)
+                                .await?;
+                        offset += written;
+                        bytes_received += literal;
                     }
-                    bytes_received += payload.chunk.len() as u64;
-                    pb.set_position((handle.start + bytes_received).min(handle.size));
-                    writer.write_all(&payload.chunk).await?;
                 }
-                Message::FileTransferComplete => break,
-                _ => return Err(anyhow!("Unexpected message during transfer")),
+                pb.set_position(offset);
             }
-        }
-
-        writer.shutdown().await?;
+            writer.shutdown().await?;
+            Ok((hasher, offset, bytes_received))
+        };
+        let (expected, (hasher, offset, bytes_received)) = tokio::try_join!(receive, write)?;
 
         pb.finish_and_clear();
 
+        // Verify file size and content
+        let digest = finalize(hasher);
+        Connection::check_and_finish_download(&handle, offset, &expected, &digest).await?;
+        if let Some(path) = &handle.path {
+            handle.metadata.apply(path)?;
+        }
+
         let elapsed = before.elapsed();
         let mb_received = bytes_received as f64 / 1_048_576.0;
         let elapsed_secs = elapsed.as_secs_f64().max(0.001);
-        println!(
-            "{} {} ({:.1} MB, {:.1} MB/s)",
+        print_status(format!(
+            "{} {} ({:.1} MB, {:.1} MB/s) {}",
             "Received".green(),
             handle.name,
             mb_received,
-            mb_received / elapsed_secs
-        );
-
-        // Verify file size
-        let file = std::fs::File::open(&handle.name)?;
-        Connection::check_and_finish_download(file, handle.name, handle.size).await?;
+            mb_received / elapsed_secs,
+            format!("digest:{}", to_hex(&digest)).dimmed()
+        ));
         Ok(())
     }
 
     pub async fn check_and_finish_download(
-        file: std::fs::File,
-        _filename: String,
-        size: u64,
+        handle: &StdFileHandle,
+        written: u64,
+        expected: &FileDigest,
+        actual: &FileDigest,
     ) -> Result<()> {
-        let metadata = file.metadata()?;
-        if metadata.len() == size {
+        let (Some(path), Some(part_path)) = (&handle.path, handle.part_path()) else {
+            if handle.size.is_some_and(|size| size != written) {
+                return Err(anyhow!("Received data does not match expected size"));
+            }
+            if expected != actual {
+                // Data already written to stdout can't be taken back
+                return Err(anyhow!(
+                    "Data written to stdout failed content verification (expected {}, got {})",
+                    to_hex(expected),
+                    to_hex(actual)
+                ));
+            }
             return Ok(());
+        };
+        // Make sure the data is on disk before it is moved into place
+        let file = std::fs::OpenOptions::new().write(true).open(&part_path)?;
+        file.sync_all()?;
+        let len = file.metadata()?.len();
+        if handle.size.is_some_and(|size| size != len) {
+            // Leave the partial file in place so the download can resume
+            return Err(anyhow!(
+                "Downloaded file does not match expected size. Try again"
+            ));
         }
-        return Err(anyhow!(
-            "Downloaded file does not match expected size. Try again"
-        ));
+        if expected == actual {
+            tokio::fs::rename(&part_path, path).await?;
+            return Ok(());
+        }
+        // Move the file aside so it can't be mistaken for a good copy
+        let quarantine = append_suffix(path, QUARANTINE_SUFFIX);
+        tokio::fs::rename(&part_path, &quarantine).await?;
+        Err(anyhow!(
+            "Downloaded file {:?} failed content verification (expected {}, got {}). Moved to {:?}",
+            path,
+            to_hex(expected),
+            to_hex(actual),
+            quarantine
+        ))
+    }
+}
+
+fn decompress_literals(
+    instructions: Vec<DeltaInstruction>,
+    use_compression: bool,
+) -> Result<Vec<DeltaInstruction>> {
+    instructions
+        .into_iter()
+        .map(|instruction| match instruction {
+            DeltaInstruction::Literal(chunk) => Ok(DeltaInstruction::Literal(decompress_chunk(
+                chunk,
+                use_compression,
+            )?)),
+            copy => Ok(copy),
+        })
+        .collect()
+}
+
+fn compress_chunk(raw: &[u8], use_compression: bool) -> Result<Bytes> {
+    if use_compression {
+        Ok(Bytes::from(zstd::bulk::compress(raw, ZSTD_COMPRESSION_LEVEL)?))
+    } else {
+        Ok(Bytes::copy_from_slice(raw))
+    }
+}
+
+fn decompress_chunk(chunk: Bytes, use_compression: bool) -> Result<Bytes> {
+    // Chunks decompress to at most BUFFER_SIZE bytes
+    if use_compression {
+        Ok(Bytes::from(zstd::bulk::decompress(&chunk, BUFFER_SIZE)?))
+    } else {
+        Ok(chunk)
+    }
+}
+
+// Data waiting to be written by the receiver, in the order it was sent
+enum Block {
+    Data {
+        start: u64,
+        job: JoinHandle<Result<Bytes>>,
+    },
+    Hole {
+        start: u64,
+        len: u64,
+    },
+    Delta(JoinHandle<Result<Vec<DeltaInstruction>>>),
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        // The read half keeps the socket open, so stop it to hang up
+        self.reader.abort();
+    }
+}
+
+async fn write_frames(
+    mut sink: SplitSink<MessageStream, Bytes>,
+    mut frames: mpsc::Receiver<JoinHandle<Result<Bytes>>>,
+) -> Result<()> {
+    while let Some(job) = frames.recv().await {
+        let frame = job.await??;
+        sink.send(frame).await.map_err(|e| anyhow!(e.to_string()))?;
+    }
+    Ok(())
+}
+
+async fn read_frames(
+    mut stream: SplitStream<MessageStream>,
+    messages: mpsc::Sender<JoinHandle<Result<Message>>>,
+    mut inbound_cipher: CipherState,
+) {
+    while let Some(frame) = stream.next().await {
+        let failed = frame.is_err();
+        let (crypt, nonce) = inbound_cipher.next();
+        let job = tokio::task::spawn_blocking(move || match frame {
+            Ok(frame) => Message::deserialize(crypt.decrypt(nonce, frame.freeze())?),
+            Err(e) => Err(anyhow!(e.to_string())),
+        });
+        if messages.send(job).await.is_err() || failed {
+            return;
+        }
+    }
+}
+
+// Fills the buffer unless the reader hits EOF, returning the number of bytes read
+async fn read_chunk<R: AsyncRead + Unpin>(reader: &mut R, buffer: &mut [u8]) -> Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        let n = reader.read(&mut buffer[filled..]).await?;
+        if n == 0 {
+            break;
+        }
+        filled += n;
     }
+    Ok(filled)
 }
@@ -1,48 +1,273 @@
-use crate::conf::NONCE_SIZE;
-use aes_gcm::aead::{Aead, NewAead};
-use aes_gcm::{Aes256Gcm, Key, Nonce};
+use crate::conf::REKEY_INTERVAL;
+
+use aes_gcm::aead::{Aead, Key, NewAead, Nonce};
+use aes_gcm::Aes256Gcm;
 use anyhow::{anyhow, Result};
-use bytes::{Bytes, BytesMut};
+use bytes::Bytes;
+use chacha20poly1305::ChaCha20Poly1305;
+use hkdf::Hkdf;
+use sha2::Sha256;
+use std::fmt;
+use std::sync::Arc;
+
+pub type SecretKey = [u8; 32];
 
-use rand::{thread_rng, Rng};
+// Encrypts and decrypts the frames of a connection. Nonces aren't sent, both
+// sides number the frames in each direction instead.
+pub trait Crypt: Send + Sync {
+    fn encrypt(&self, nonce: u64, plaintext: Bytes) -> Result<Bytes>;
 
-pub struct Crypt {
-    cipher: Aes256Gcm,
-    arr: [u8; NONCE_SIZE],
+    fn decrypt(&self, nonce: u64, ciphertext: Bytes) -> Result<Bytes>;
 }
 
-impl Crypt {
-    pub fn new(key: &Vec<u8>) -> Crypt {
-        let key = Key::from_slice(&key[..]);
-        Crypt {
-            cipher: Aes256Gcm::new(key),
-            arr: [0u8; NONCE_SIZE],
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Sender,
+    Receiver,
+}
+
+// Each direction of a connection has its own key
+pub struct ConnectionKeys {
+    pub outbound: SecretKey,
+    pub inbound: SecretKey,
+}
+
+impl ConnectionKeys {
+    // `secret` is the session key, `index` numbers the session's connections
+    pub fn derive(secret: &[u8], role: Role, index: u16) -> ConnectionKeys {
+        let hkdf = Hkdf::<Sha256>::new(None, secret);
+        let expand = |label: &[u8]| {
+            let mut key = SecretKey::default();
+            hkdf.expand_multi_info(&[label, &index.to_le_bytes()], &mut key)
+                .expect("32 bytes is a valid HKDF output length");
+            key
+        };
+        let to_receiver = expand(b"ruck sender to receiver");
+        let to_sender = expand(b"ruck receiver to sender");
+        match role {
+            Role::Sender => ConnectionKeys {
+                outbound: to_receiver,
+                inbound: to_sender,
+            },
+            Role::Receiver => ConnectionKeys {
+                outbound: to_sender,
+                inbound: to_receiver,
+            },
         }
     }
+}
 
-    // Returns wire format, includes nonce as prefix
-    pub fn encrypt(&mut self, plaintext: Bytes) -> Result<Bytes> {
-        thread_rng().try_fill(&mut self.arr[..])?;
-        let nonce = Nonce::from_slice(&self.arr);
-        match self.cipher.encrypt(nonce, plaintext.as_ref()) {
-            Ok(body) => {
-                let mut buffer = BytesMut::with_capacity(NONCE_SIZE + body.len());
-                buffer.extend_from_slice(nonce);
-                buffer.extend_from_slice(&body);
-                Ok(buffer.freeze())
-            }
+// Numbers the frames going one way. A frame that was replayed, reordered or
+// dropped is decrypted with the wrong nonce, so it fails authentication.
+pub struct CipherState {
+    cipher: Cipher,
+    key: SecretKey,
+    crypt: Arc<dyn Crypt>,
+    counter: u64,
+    rekey_interval: u64,
+}
+
+impl CipherState {
+    pub fn new(cipher: Cipher, key: SecretKey) -> CipherState {
+        CipherState::with_rekey_interval(cipher, key, REKEY_INTERVAL)
+    }
+
+    fn with_rekey_interval(cipher: Cipher, key: SecretKey, rekey_interval: u64) -> CipherState {
+        CipherState {
+            cipher,
+            key,
+            crypt: cipher.new_crypt(&key),
+            counter: 0,
+            rekey_interval,
+        }
+    }
+
+    // Returns the crypt and nonce for the next frame. Both sides move on to a
+    // new key after the same number of frames, long before a counter could wrap.
+    pub fn next(&mut self) -> (Arc<dyn Crypt>, u64) {
+        if self.counter == self.rekey_interval {
+            let mut key = SecretKey::default();
+            Hkdf::<Sha256>::new(None, &self.key)
+                .expand(b"ruck rekey", &mut key)
+                .expect("32 bytes is a valid HKDF output length");
+            self.key = key;
+            self.crypt = self.cipher.new_crypt(&self.key);
+            self.counter = 0;
+        }
+        let nonce = self.counter;
+        self.counter += 1;
+        (self.crypt.clone(), nonce)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cipher {
+    Aes256Gcm,
+    ChaCha20Poly1305,
+}
+
+impl Cipher {
+    // Also the order used when the two sides' preferences are a tie.
+    // ChaCha20-Poly1305 comes first since it is fast without hardware support.
+    const ALL: [Cipher; 2] = [Cipher::ChaCha20Poly1305, Cipher::Aes256Gcm];
+
+    pub fn id(self) -> u8 {
+        match self {
+            Cipher::Aes256Gcm => 1,
+            Cipher::ChaCha20Poly1305 => 2,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Cipher> {
+        Cipher::ALL.into_iter().find(|cipher| cipher.id() == id)
+    }
+
+    // Supported ciphers, fastest on this machine first
+    pub fn preferred() -> Vec<Cipher> {
+        if has_aes_instructions() {
+            vec![Cipher::Aes256Gcm, Cipher::ChaCha20Poly1305]
+        } else {
+            vec![Cipher::ChaCha20Poly1305, Cipher::Aes256Gcm]
+        }
+    }
+
+    // Picks the cipher both sides rank highest, judged by whichever side ranks
+    // it lower. Gives the same answer on both sides.
+    pub fn choose(ours: &[Cipher], theirs: &[Cipher]) -> Option<Cipher> {
+        Cipher::ALL
+            .into_iter()
+            .filter_map(|cipher| {
+                let ours = ours.iter().position(|&c| c == cipher)?;
+                let theirs = theirs.iter().position(|&c| c == cipher)?;
+                Some((ours.max(theirs), cipher))
+            })
+            .min_by_key(|(rank, _)| *rank)
+            .map(|(_, cipher)| cipher)
+    }
+
+    pub fn new_crypt(self, key: &[u8]) -> Arc<dyn Crypt> {
+        match self {
+            Cipher::Aes256Gcm => Arc::new(AeadCrypt::<Aes256Gcm>::new(key)),
+            Cipher::ChaCha20Poly1305 => Arc::new(AeadCrypt::<ChaCha20Poly1305>::new(key)),
+        }
+    }
+}
+
+impl fmt::Display for Cipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Cipher::Aes256Gcm => write!(f, "AES-256-GCM"),
+            Cipher::ChaCha20Poly1305 => write!(f, "ChaCha20-Poly1305"),
+        }
+    }
+}
+
+// The aes crate only uses hardware instructions on x86
+fn has_aes_instructions() -> bool {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        is_x86_feature_detected!("aes") && is_x86_feature_detected!("pclmulqdq")
+    }
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+    {
+        false
+    }
+}
+
+// Any AEAD with a nonce of at least 64 bits
+struct AeadCrypt<C> {
+    cipher: C,
+}
+
+impl<C: NewAead> AeadCrypt<C> {
+    fn new(key: &[u8]) -> AeadCrypt<C> {
+        AeadCrypt {
+            cipher: C::new(Key::<C>::from_slice(key)),
+        }
+    }
+}
+
+impl<C: Aead + Send + Sync> AeadCrypt<C> {
+    fn nonce(counter: u64) -> Nonce<C> {
+        let mut nonce = Nonce::<C>::default();
+        nonce[..8].copy_from_slice(&counter.to_le_bytes());
+        nonce
+    }
+}
+
+impl<C: Aead + Send + Sync> Crypt for AeadCrypt<C> {
+    fn encrypt(&self, nonce: u64, plaintext: Bytes) -> Result<Bytes> {
+        match self.cipher.encrypt(&Self::nonce(nonce), plaintext.as_ref()) {
+            Ok(body) => Ok(Bytes::from(body)),
             Err(e) => Err(anyhow!(e.to_string())),
         }
     }
 
-    // Accepts wire format, includes nonce as prefix
-    pub fn decrypt(&self, ciphertext: Bytes) -> Result<Bytes> {
-        let mut ciphertext_body = ciphertext;
-        let nonce_bytes = ciphertext_body.split_to(NONCE_SIZE);
-        let nonce = Nonce::from_slice(&nonce_bytes);
-        match self.cipher.decrypt(nonce, ciphertext_body.as_ref()) {
+    fn decrypt(&self, nonce: u64, ciphertext: Bytes) -> Result<Bytes> {
+        match self
+            .cipher
+            .decrypt(&Self::nonce(nonce), ciphertext.as_ref())
+        {
             Ok(payload) => Ok(Bytes::from(payload)),
-            Err(e) => Err(anyhow!(e.to_string())),
+            Err(_) => Err(anyhow!(
+                "Rejected a message that was tampered with, replayed or reordered"
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: SecretKey = [7; 32];
+
+    fn frames(state: &mut CipherState, count: usize) -> Vec<Bytes> {
+        (0..count)
+            .map(|i| {
+                let (crypt, nonce) = state.next();
+                crypt.encrypt(nonce, Bytes::from(format!("frame {}", i))).unwrap()
+            })
+            .collect()
+    }
+
+    fn open(state: &mut CipherState, frame: &Bytes) -> Result<Bytes> {
+        let (crypt, nonce) = state.next();
+        crypt.decrypt(nonce, frame.clone())
+    }
+
+    #[test]
+    fn rejects_replayed_and_reordered_frames() {
+        for cipher in Cipher::ALL {
+            let sent = frames(&mut CipherState::new(cipher, KEY), 3);
+
+            let mut replayed = CipherState::new(cipher, KEY);
+            assert!(open(&mut replayed, &sent[0]).is_ok());
+            assert!(open(&mut replayed, &sent[0]).is_err());
+
+            let mut reordered = CipherState::new(cipher, KEY);
+            assert!(open(&mut reordered, &sent[1]).is_err());
+
+            let mut dropped = CipherState::new(cipher, KEY);
+            assert!(open(&mut dropped, &sent[0]).is_ok());
+            assert!(open(&mut dropped, &sent[2]).is_err());
+        }
+    }
+
+    #[test]
+    fn both_sides_rekey_in_step() {
+        for cipher in Cipher::ALL {
+            let mut sender = CipherState::with_rekey_interval(cipher, KEY, 4);
+            let mut receiver = CipherState::with_rekey_interval(cipher, KEY, 4);
+            let sent = frames(&mut sender, 10);
+            for (i, frame) in sent.iter().enumerate() {
+                assert_eq!(open(&mut receiver, frame).unwrap(), format!("frame {}", i));
+            }
+
+            // Frames after the rekey are no longer readable with the first key
+            let mut stale = CipherState::with_rekey_interval(cipher, KEY, u64::MAX);
+            frames(&mut stale, 4);
+            assert!(open(&mut stale, &sent[4]).is_err());
         }
     }
 }
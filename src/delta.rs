@@ -0,0 +1,385 @@
+use crate::conf::{BUFFER_SIZE, DELTA_MAX_BLOCKS, DELTA_MIN_BLOCK_SIZE};
+use crate::file::Output;
+use crate::hash::Hasher;
+
+use anyhow::{anyhow, Result};
+use blake2::digest::consts::U16;
+use blake2::{Blake2b, Digest};
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::SeekFrom;
+use std::path::Path;
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+// Strong block hashes only need to rule out weak hash collisions,
+// the whole file is still checked against the sender's digest
+type StrongHasher = Blake2b<U16>;
+type StrongHash = [u8; 16];
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BlockSignature {
+    pub weak: u32,
+    pub strong: StrongHash,
+}
+
+// Signatures of every full block of the receiver's existing copy
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Signatures {
+    pub block_size: u64,
+    pub blocks: Vec<BlockSignature>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum DeltaInstruction {
+    // Copy `count` blocks from the receiver's copy, starting at block `block`
+    Copy { block: u64, count: u64 },
+    // New data, compressed like a regular chunk
+    Literal(Bytes),
+}
+
+impl Signatures {
+    // Reads the receiver's copy at `path` block by block
+    pub async fn from_file(path: &Path) -> Result<Signatures> {
+        let mut file = File::open(path).await?;
+        let block_size = block_size(file.metadata().await?.len());
+        let mut buffer = vec![0u8; block_size as usize];
+        let mut blocks = Vec::new();
+        loop {
+            // A trailing partial block is sent as a literal instead
+            match file.read_exact(&mut buffer).await {
+                Ok(_) => blocks.push(BlockSignature {
+                    weak: RollingChecksum::new(&buffer).digest(),
+                    strong: strong_hash(&buffer),
+                }),
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(Signatures { block_size, blocks })
+    }
+}
+
+// Roughly the square root of the file size, like rsync, while keeping
+// the signature message small for very large files
+fn block_size(len: u64) -> u64 {
+    ((len as f64).sqrt() as u64)
+        .max(len / DELTA_MAX_BLOCKS)
+        .max(DELTA_MIN_BLOCK_SIZE)
+}
+
+fn strong_hash(block: &[u8]) -> StrongHash {
+    StrongHasher::digest(block).into()
+}
+
+// The rsync rolling checksum, which can slide along one byte at a time
+struct RollingChecksum {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl RollingChecksum {
+    fn new(window: &[u8]) -> RollingChecksum {
+        let len = window.len() as u32;
+        let mut a: u32 = 0;
+        let mut b: u32 = 0;
+        for (i, &byte) in window.iter().enumerate() {
+            a = a.wrapping_add(byte as u32);
+            b = b.wrapping_add((len - i as u32).wrapping_mul(byte as u32));
+        }
+        RollingChecksum { a, b, len }
+    }
+
+    fn roll(&mut self, out: u8, into: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(into as u32);
+        self.b = self
+            .b
+            .wrapping_sub(self.len.wrapping_mul(out as u32))
+            .wrapping_add(self.a);
+    }
+
+    fn digest(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+// Turns the sender's data into instructions against the receiver's blocks.
+// Data is pushed in as it is read, so the file never has to fit in memory.
+pub struct DeltaEncoder {
+    signatures: Signatures,
+    lookup: HashMap<u32, Vec<u64>>,
+    // Cheap first check on the high bits of the weak checksum, most
+    // windows don't match any block
+    filter: Vec<bool>,
+    buffer: Vec<u8>,
+    // Start of the bytes not yet sent as a literal or copy
+    literal_start: usize,
+    // Start of the window being matched
+    pos: usize,
+    rolling: Option<RollingChecksum>,
+}
+
+impl DeltaEncoder {
+    pub fn new(signatures: Signatures) -> DeltaEncoder {
+        let mut lookup: HashMap<u32, Vec<u64>> = HashMap::new();
+        let mut filter = vec![false; 1 << 16];
+        for (i, block) in signatures.blocks.iter().enumerate() {
+            lookup.entry(block.weak).or_default().push(i as u64);
+            filter[(block.weak >> 16) as usize] = true;
+        }
+        DeltaEncoder {
+            signatures,
+            lookup,
+            filter,
+            buffer: Vec::new(),
+            literal_start: 0,
+            pos: 0,
+            rolling: None,
+        }
+    }
+
+    pub fn push(&mut self, data: &[u8]) -> Vec<DeltaInstruction> {
+        // Drop everything that has already been turned into instructions
+        self.buffer.drain(..self.literal_start);
+        self.pos -= self.literal_start;
+        self.literal_start = 0;
+        self.buffer.extend_from_slice(data);
+
+        let block_size = self.signatures.block_size as usize;
+        let mut instructions = Vec::new();
+        while self.buffer.len() - self.pos >= block_size {
+            let window = &self.buffer[self.pos..self.pos + block_size];
+            let weak = self
+                .rolling
+                .get_or_insert_with(|| RollingChecksum::new(window))
+                .digest();
+            if let Some(block) = self.find_block(weak, window) {
+                self.emit_literal(self.pos, &mut instructions);
+                emit_copy(block, &mut instructions);
+                self.pos += block_size;
+                self.literal_start = self.pos;
+                self.rolling = None;
+                continue;
+            }
+            // Slide the window along by one byte
+            match (self.rolling.as_mut(), self.buffer.get(self.pos + block_size)) {
+                (Some(rolling), Some(&into)) => rolling.roll(self.buffer[self.pos], into),
+                _ => self.rolling = None,
+            }
+            self.pos += 1;
+            if self.pos - self.literal_start >= BUFFER_SIZE {
+                self.emit_literal(self.pos, &mut instructions);
+                self.literal_start = self.pos;
+            }
+        }
+        instructions
+    }
+
+    // Sends whatever is left over as literals
+    pub fn finish(&mut self) -> Vec<DeltaInstruction> {
+        let mut instructions = Vec::new();
+        self.emit_literal(self.buffer.len(), &mut instructions);
+        self.literal_start = self.buffer.len();
+        self.pos = self.buffer.len();
+        instructions
+    }
+
+    fn find_block(&self, weak: u32, window: &[u8]) -> Option<u64> {
+        if !self.filter[(weak >> 16) as usize] {
+            return None;
+        }
+        let candidates = self.lookup.get(&weak)?;
+        let strong = strong_hash(window);
+        candidates
+            .iter()
+            .copied()
+            .find(|&i| self.signatures.blocks[i as usize].strong == strong)
+    }
+
+    fn emit_literal(&self, end: usize, instructions: &mut Vec<DeltaInstruction>) {
+        for chunk in self.buffer[self.literal_start..end].chunks(BUFFER_SIZE) {
+            instructions.push(DeltaInstruction::Literal(Bytes::copy_from_slice(chunk)));
+        }
+    }
+}
+
+// Consecutive blocks are merged into a single copy
+fn emit_copy(block: u64, instructions: &mut Vec<DeltaInstruction>) {
+    if let Some(DeltaInstruction::Copy { block: first, count }) = instructions.last_mut() {
+        if *first + *count == block {
+            *count += 1;
+            return;
+        }
+    }
+    instructions.push(DeltaInstruction::Copy { block, count: 1 });
+}
+
+// Writes the result of delta instructions, returning the total number of
+// bytes written and how many of them were new
+pub async fn apply_delta(
+    instructions: Vec<DeltaInstruction>,
+    signatures: &Signatures,
+    basis: &mut File,
+    writer: &mut Output,
+    hasher: &mut Hasher,
+) -> Result<(u64, u64)> {
+    let mut written: u64 = 0;
+    let mut literal: u64 = 0;
+    let mut buffer = vec![0u8; BUFFER_SIZE];
+    for instruction in instructions {
+        match instruction {
+            DeltaInstruction::Copy { block, count } => {
+                let blocks = signatures.blocks.len() as u64;
+                if block.checked_add(count).is_none_or(|end| end > blocks) {
+                    return Err(anyhow!("Delta refers to a block that was never sent"));
+                }
+                basis
+                    .seek(SeekFrom::Start(block * signatures.block_size))
+                    .await?;
+                let mut remaining = count * signatures.block_size;
+                while remaining > 0 {
+                    let n = remaining.min(BUFFER_SIZE as u64) as usize;
+                    basis.read_exact(&mut buffer[..n]).await?;
+                    hasher.update(&buffer[..n]);
+                    writer.write_all(&buffer[..n]).await?;
+                    remaining -= n as u64;
+                    written += n as u64;
+                }
+            }
+            DeltaInstruction::Literal(chunk) => {
+                hasher.update(&chunk);
+                writer.write_all(&chunk).await?;
+                written += chunk.len() as u64;
+                literal += chunk.len() as u64;
+            }
+        }
+    }
+    Ok((written, literal))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::finalize;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    const BLOCK: usize = DELTA_MIN_BLOCK_SIZE as usize;
+
+    fn random_bytes(len: usize, seed: u64) -> Vec<u8> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        (0..len).map(|_| rng.gen()).collect()
+    }
+
+    // Encodes `new` against `basis` and rebuilds it from the instructions,
+    // returning the rebuilt file and how many literal bytes were sent
+    async fn round_trip(name: &str, basis: &[u8], new: &[u8]) -> (Vec<u8>, u64) {
+        let dir = std::env::temp_dir().join(format!("ruck-delta-{}-{}", std::process::id(), name));
+        std::fs::create_dir_all(&dir).unwrap();
+        let basis_path = dir.join("basis");
+        let out_path = dir.join("out");
+        std::fs::write(&basis_path, basis).unwrap();
+        let signatures = Signatures::from_file(&basis_path).await.unwrap();
+
+        // Data arrives in pieces that don't line up with blocks
+        let mut encoder = DeltaEncoder::new(signatures.clone());
+        let mut instructions = Vec::new();
+        for piece in new.chunks(7919) {
+            instructions.extend(encoder.push(piece));
+        }
+        instructions.extend(encoder.finish());
+
+        let mut basis_file = File::open(&basis_path).await.unwrap();
+        let mut writer = Output::File(File::create(&out_path).await.unwrap());
+        let mut hasher = Hasher::default();
+        let (written, literal) = apply_delta(
+            instructions,
+            &signatures,
+            &mut basis_file,
+            &mut writer,
+            &mut hasher,
+        )
+        .await
+        .unwrap();
+        writer.shutdown().await.unwrap();
+        let rebuilt = std::fs::read(&out_path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(written, rebuilt.len() as u64);
+        let mut expected = Hasher::default();
+        expected.update(new);
+        assert_eq!(finalize(hasher), finalize(expected));
+        (rebuilt, literal)
+    }
+
+    #[test]
+    fn rolling_checksum_matches_fresh_checksum() {
+        for data in [random_bytes(20_000, 1), vec![0xff; 20_000]] {
+            let window = 1000;
+            let mut rolling = RollingChecksum::new(&data[..window]);
+            for i in 1..=data.len() - window {
+                rolling.roll(data[i - 1], data[i + window - 1]);
+                let fresh = RollingChecksum::new(&data[i..i + window]);
+                assert_eq!(rolling.digest(), fresh.digest());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn unchanged_file_is_copied() {
+        let basis = random_bytes(64 * BLOCK, 2);
+        let (rebuilt, literal) = round_trip("unchanged", &basis, &basis).await;
+        assert_eq!(rebuilt, basis);
+        assert_eq!(literal, 0);
+    }
+
+    #[tokio::test]
+    async fn rebuilds_insertions() {
+        let basis = random_bytes(64 * BLOCK, 3);
+        let inserted = random_bytes(100, 4);
+        for (name, at) in [("insert-inside", 10_000), ("insert-boundary", 5 * BLOCK)] {
+            let new = [&basis[..at], &inserted, &basis[at..]].concat();
+            let (rebuilt, literal) = round_trip(name, &basis, &new).await;
+            assert_eq!(rebuilt, new);
+            assert!(literal <= (inserted.len() + BLOCK) as u64);
+        }
+    }
+
+    #[tokio::test]
+    async fn rebuilds_deletions() {
+        let basis = random_bytes(64 * BLOCK, 5);
+        for (name, range) in [
+            ("delete-block", 3 * BLOCK..4 * BLOCK),
+            ("delete-inside", 10_000..10_100),
+        ] {
+            let new = [&basis[..range.start], &basis[range.end..]].concat();
+            let (rebuilt, literal) = round_trip(name, &basis, &new).await;
+            assert_eq!(rebuilt, new);
+            assert!(literal <= 2 * BLOCK as u64);
+        }
+    }
+
+    #[tokio::test]
+    async fn rebuilds_changes_at_block_boundaries() {
+        let basis = random_bytes(64 * BLOCK, 6);
+        let mut new = basis.clone();
+        new[8 * BLOCK - 1] ^= 0xff;
+        new[8 * BLOCK] ^= 0xff;
+        new[63 * BLOCK] ^= 0xff;
+        new.extend_from_slice(&random_bytes(1000, 7));
+        let (rebuilt, literal) = round_trip("change", &basis, &new).await;
+        assert_eq!(rebuilt, new);
+        assert!(literal <= (3 * BLOCK + 1000) as u64);
+    }
+
+    #[tokio::test]
+    async fn basis_shorter_than_a_block_is_sent_as_literals() {
+        let basis = random_bytes(100, 8);
+        let new = [&basis[..], &random_bytes(10_000, 9)].concat();
+        let (rebuilt, literal) = round_trip("short", &basis, &new).await;
+        assert_eq!(rebuilt, new);
+        assert_eq!(literal, new.len() as u64);
+    }
+}
@@ -1,14 +1,25 @@
+use crate::conf::{BUFFER_SIZE, PART_SUFFIX};
+use crate::delta::Signatures;
+use crate::hash::{finalize, hash_file_prefix, FileDigest, Hasher};
+use crate::sparse::data_regions;
+
 use anyhow::{anyhow, Result};
-use futures::future::try_join_all;
+use clap::ValueEnum;
 
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 use std::fs::Metadata;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 
-use std::io::{Seek, SeekFrom};
+use std::io::SeekFrom;
+use std::ops::Range;
+use std::pin::Pin;
+use std::task::{Context, Poll};
 
-use tokio::fs::File;
-use tracing::{debug, error};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncRead, AsyncSeekExt, AsyncWrite, AsyncWriteExt, ReadBuf, Stdin, Stdout};
+use tracing::{debug, warn};
 
 #[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum CompressionType {
@@ -16,80 +27,387 @@ pub enum CompressionType {
     Zstd,
 }
 
+// What the receiver does when an offered file already exists locally
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum CollisionPolicy {
+    /// Replace the existing file
+    Overwrite,
+    /// Continue a partial download, otherwise overwrite
+    Resume,
+    /// Save as `file (1).txt` instead, continuing partial downloads
+    Rename,
+    /// Keep the existing file and don't download
+    Skip,
+    /// Abort before writing anything
+    Fail,
+}
+
+// How symlinks found while walking a directory are sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum SymlinkMode {
+    /// Send the file or directory the link points to
+    Follow,
+    /// Send the link itself, with its target
+    Preserve,
+    /// Leave links out
+    Skip,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum EntryKind {
+    File,
+    // Relative link target, always '/' separated. No data is transferred
+    Symlink(String),
+    // A directory with nothing else offered inside it, so it isn't lost
+    Directory,
+}
+
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ChunkHeader {
-    pub id: u8,
+    pub id: FileId,
     pub start: u64,
 }
 
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChunkRequest {
+    pub chunk_header: ChunkHeader,
+    // Digest of the receiver's existing bytes before `start`, when resuming
+    pub prefix_digest: Option<FileDigest>,
+    // The receiver follows up with signatures of its existing copy
+    pub delta: bool,
+}
+
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct FileOffer {
-    pub id: u8,
+    pub id: FileId,
     pub path: String,
-    pub size: u64,
+    // None for streams of unknown length, like stdin
+    pub size: Option<u64>,
     pub compression: CompressionType,
+    pub metadata: FileMetadata,
+    pub kind: EntryKind,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct FileMetadata {
+    // Unix permission bits, without setuid/setgid/sticky
+    pub mode: Option<u32>,
+    pub modified: Option<SystemTime>,
 }
 
+impl FileMetadata {
+    pub fn from_std(md: &Metadata) -> FileMetadata {
+        #[cfg(unix)]
+        let mode = {
+            use std::os::unix::fs::PermissionsExt;
+            Some(md.permissions().mode() & 0o777)
+        };
+        #[cfg(not(unix))]
+        let mode = None;
+        FileMetadata {
+            mode,
+            modified: md.modified().ok(),
+        }
+    }
+
+    pub fn apply(&self, path: &Path) -> Result<()> {
+        // Set the time first, permissions may make the file read-only
+        if let Some(modified) = self.modified {
+            std::fs::File::options()
+                .write(true)
+                .open(path)?
+                .set_modified(modified)?;
+        }
+        #[cfg(unix)]
+        if let Some(mode) = self.mode {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode & 0o777))?;
+        }
+        Ok(())
+    }
+}
+
+pub type FileId = u32;
+
+// `ruck-relay send -` reads from stdin, offered to the receiver under this name
+const STDIN_PATH: &str = "-";
+const STDIN_NAME: &str = "stdin";
+
+// Files are only opened when they are transferred, so large batches
+// of files don't exhaust the process's file descriptor limit.
 pub struct StdFileHandle {
-    pub id: u8,
+    pub id: FileId,
     pub name: String,
-    pub file: std::fs::File,
+    // None for stdin when sending, or stdout when receiving
+    pub path: Option<PathBuf>,
     pub start: u64,
-    pub size: u64,
+    pub size: Option<u64>,
+    // Hash of the local bytes before `start`
+    pub prefix: Hasher,
+    // Applied by the receiver once the download is verified
+    pub metadata: FileMetadata,
+    // Signatures of the receiver's existing copy, for delta transfers
+    pub delta: Option<Signatures>,
+    // Where that copy is, `path` unless the new one is saved under another name
+    pub basis: Option<PathBuf>,
 }
 
 impl StdFileHandle {
-    pub async fn new(
-        id: u8,
+    pub fn new(
+        id: FileId,
         name: String,
-        file: File,
+        path: Option<PathBuf>,
         start: u64,
-        size: u64,
-    ) -> Result<StdFileHandle> {
-        let mut file = file.into_std().await;
-        file.seek(SeekFrom::Start(start))?;
-        Ok(StdFileHandle {
+        size: Option<u64>,
+        prefix: Hasher,
+        metadata: FileMetadata,
+    ) -> StdFileHandle {
+        StdFileHandle {
             id,
             name,
-            file,
+            path,
             start,
             size,
-        })
+            prefix,
+            metadata,
+            delta: None,
+            basis: None,
+        }
+    }
+
+    pub fn to_chunk_request(&self) -> ChunkRequest {
+        let prefix_digest = if self.start > 0 {
+            Some(finalize(self.prefix.clone()))
+        } else {
+            None
+        };
+        ChunkRequest {
+            chunk_header: ChunkHeader {
+                id: self.id,
+                start: self.start,
+            },
+            prefix_digest,
+            delta: self.delta.is_some(),
+        }
+    }
+
+    // Where the receiver writes data until the download has been verified
+    pub fn part_path(&self) -> Option<PathBuf> {
+        self.path
+            .as_ref()
+            .map(|path| append_suffix(path, PART_SUFFIX))
+    }
+
+    // Discards any local bytes so the transfer starts from the beginning
+    pub async fn restart(&mut self) -> Result<()> {
+        if let Some(path) = self.part_path() {
+            OpenOptions::new().write(true).open(path).await?.set_len(0).await?;
+        }
+        self.start = 0;
+        self.prefix = Hasher::default();
+        Ok(())
+    }
+
+    // Regions from `start` that hold data, the gaps between them are holes.
+    // Streams are a single region of unknown length.
+    pub async fn data_regions(&self) -> Result<Vec<Range<u64>>> {
+        match (&self.path, self.size) {
+            (Some(path), Some(size)) => {
+                let file = std::fs::File::open(path)?;
+                let start = self.start;
+                Ok(tokio::task::spawn_blocking(move || data_regions(&file, start, size)).await??)
+            }
+            _ => Ok(std::iter::once(self.start..u64::MAX).collect()),
+        }
+    }
+
+    pub async fn open_read(&self) -> Result<Input> {
+        match &self.path {
+            Some(path) => {
+                let mut file = File::open(path).await?;
+                file.seek(SeekFrom::Start(self.start)).await?;
+                Ok(Input::File(file))
+            }
+            None => Ok(Input::Stdin(tokio::io::stdin())),
+        }
+    }
+
+    pub async fn open_write(&self) -> Result<Output> {
+        match self.part_path() {
+            Some(path) => {
+                let mut file = OpenOptions::new().write(true).open(path).await?;
+                file.seek(SeekFrom::Start(self.start)).await?;
+                Ok(Output::File(file))
+            }
+            None => Ok(Output::Stdout(tokio::io::stdout())),
+        }
+    }
+}
+
+pub enum Input {
+    File(File),
+    Stdin(Stdin),
+}
+
+impl Input {
+    // Moves past a hole without reading it
+    pub async fn skip(&mut self, len: u64) -> Result<()> {
+        match self {
+            Input::File(file) => {
+                file.seek(SeekFrom::Current(len as i64)).await?;
+                Ok(())
+            }
+            Input::Stdin(_) => Err(anyhow!("Can't skip ahead in stdin")),
+        }
+    }
+}
+
+impl AsyncRead for Input {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Input::File(file) => Pin::new(file).poll_read(cx, buf),
+            Input::Stdin(stdin) => Pin::new(stdin).poll_read(cx, buf),
+        }
+    }
+}
+
+pub enum Output {
+    File(File),
+    Stdout(Stdout),
+}
+
+impl Output {
+    // Files get a real hole so they stay sparse, streams get the zeros
+    pub async fn write_hole(&mut self, len: u64) -> Result<()> {
+        match self {
+            Output::File(file) => {
+                file.flush().await?;
+                let end = file.seek(SeekFrom::Current(len as i64)).await?;
+                file.set_len(end).await?;
+            }
+            Output::Stdout(stdout) => {
+                let zeros = vec![0u8; BUFFER_SIZE];
+                let mut remaining = len;
+                while remaining > 0 {
+                    let n = remaining.min(BUFFER_SIZE as u64) as usize;
+                    stdout.write_all(&zeros[..n]).await?;
+                    remaining -= n as u64;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl AsyncWrite for Output {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            Output::File(file) => Pin::new(file).poll_write(cx, buf),
+            Output::Stdout(stdout) => Pin::new(stdout).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Output::File(file) => Pin::new(file).poll_flush(cx),
+            Output::Stdout(stdout) => Pin::new(stdout).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Output::File(file) => Pin::new(file).poll_shutdown(cx),
+            Output::Stdout(stdout) => Pin::new(stdout).poll_shutdown(cx),
+        }
     }
 }
 
 pub struct FileHandle {
-    pub id: u8,
-    pub file: File,
-    pub md: Metadata,
-    pub path: PathBuf,
+    pub id: FileId,
+    // None when reading from stdin or sending a symlink
+    pub md: Option<Metadata>,
+    // None when reading from stdin
+    pub path: Option<PathBuf>,
+    // Relative path offered to the receiver, always '/' separated
+    pub name: String,
+    pub kind: EntryKind,
 }
 
 impl FileHandle {
-    pub async fn new(id: u8, path: PathBuf) -> Result<FileHandle> {
-        let file = File::open(&path).await?;
-        let md = file.metadata().await?;
-        let fh = FileHandle { id, file, md, path };
-        return Ok(fh);
+    pub async fn new(id: FileId, path: PathBuf, name: String) -> Result<FileHandle> {
+        // Open once to fail early on unreadable files
+        let md = File::open(&path).await?.metadata().await?;
+        let fh = FileHandle {
+            id,
+            md: Some(md),
+            path: Some(path),
+            name,
+            kind: EntryKind::File,
+        };
+        Ok(fh)
+    }
+
+    pub fn symlink(id: FileId, path: PathBuf, name: String, target: String) -> FileHandle {
+        FileHandle {
+            id,
+            md: None,
+            path: Some(path),
+            name,
+            kind: EntryKind::Symlink(target),
+        }
+    }
+
+    pub fn directory(id: FileId, path: PathBuf, name: String) -> FileHandle {
+        FileHandle {
+            id,
+            md: None,
+            path: Some(path),
+            name,
+            kind: EntryKind::Directory,
+        }
+    }
+
+    pub fn stdin(id: FileId) -> FileHandle {
+        FileHandle {
+            id,
+            md: None,
+            path: None,
+            name: STDIN_NAME.to_string(),
+            kind: EntryKind::File,
+        }
+    }
+
+    pub fn size(&self) -> Option<u64> {
+        match self.kind {
+            EntryKind::File => self.md.as_ref().map(Metadata::len),
+            EntryKind::Symlink(_) | EntryKind::Directory => Some(0),
+        }
     }
 
     pub async fn to_stds(
         file_handles: Vec<FileHandle>,
-        chunk_headers: Vec<ChunkHeader>,
+        chunk_requests: Vec<ChunkRequest>,
     ) -> Vec<StdFileHandle> {
+        let mut requests: HashMap<FileId, ChunkRequest> = chunk_requests
+            .into_iter()
+            .map(|request| (request.chunk_header.id, request))
+            .collect();
         let mut ret = Vec::new();
         for handle in file_handles {
-            let chunk = chunk_headers.iter().find(|chunk| handle.id == chunk.id);
-            match chunk {
-                Some(chunk) => {
-                    match handle.to_std(chunk).await {
-                        Ok(std_file_handle) => {
-                            ret.push(std_file_handle);
-                        }
-                        _ => error!("Error seeking in file"),
-                    };
+            match requests.remove(&handle.id) {
+                // Symlinks are created by the receiver from the offer alone
+                Some(request) if handle.kind == EntryKind::File => {
+                    ret.push(handle.into_std(request).await)
                 }
-                None => {
+                _ => {
                     debug!(path = ?handle.path, "Skipping file, not in requested chunks");
                 }
             }
@@ -97,43 +415,163 @@ impl FileHandle {
         ret
     }
 
-    async fn to_std(self, chunk_header: &ChunkHeader) -> Result<StdFileHandle> {
+    // Only honors a resume offset if the receiver's prefix matches our own bytes,
+    // otherwise the transfer restarts from zero
+    async fn into_std(self, request: ChunkRequest) -> StdFileHandle {
+        let size = self.size();
+        let mut start = request.chunk_header.start;
+        let mut prefix = Hasher::default();
+        if start > 0 {
+            let ours = match &self.path {
+                Some(path) => hash_file_prefix(path, start).await,
+                None => Err(anyhow!("Cannot resume a stream")),
+            };
+            match (ours, request.prefix_digest) {
+                (Ok(hasher), Some(theirs)) if finalize(hasher.clone()) == theirs => prefix = hasher,
+                _ => {
+                    debug!(path = ?self.path, start, "Receiver prefix does not match, restarting");
+                    start = 0;
+                }
+            }
+        }
         StdFileHandle::new(
             self.id,
-            pathbuf_to_string(&self.path)?,
-            self.file,
-            chunk_header.start,
-            self.md.len(),
+            self.name,
+            self.path,
+            start,
+            size,
+            prefix,
+            FileMetadata::default(),
         )
-        .await
     }
 
-    pub fn to_file_offer(&self) -> Result<FileOffer> {
-        let path = pathbuf_to_string(&self.path)?;
+    pub fn to_file_offer(&self) -> FileOffer {
+        let path = self.name.clone();
         let compression = if should_compress(&path) {
             CompressionType::Zstd
         } else {
             CompressionType::None
         };
-        Ok(FileOffer {
+        FileOffer {
             id: self.id,
             path,
-            size: self.md.len(),
+            size: self.size(),
             compression,
-        })
+            metadata: self.md.as_ref().map(FileMetadata::from_std).unwrap_or_default(),
+            kind: self.kind.clone(),
+        }
     }
 
-    pub async fn get_file_handles(file_paths: &Vec<PathBuf>) -> Result<Vec<FileHandle>> {
-        let tasks = file_paths
-            .into_iter()
-            .enumerate()
-            .map(|(idx, path)| FileHandle::new(idx.try_into().unwrap(), path.to_path_buf()));
-        let handles = try_join_all(tasks).await?;
+    // Only taken when the receiver asks, as it means reading the whole file
+    pub async fn digest(&self) -> Result<FileDigest> {
+        match (&self.path, self.size(), &self.kind) {
+            (Some(path), Some(size), EntryKind::File) => {
+                Ok(finalize(hash_file_prefix(path, size).await?))
+            }
+            _ => Err(anyhow!("No digest for {}", self.name)),
+        }
+    }
+
+    pub async fn get_file_handles(
+        file_paths: &[PathBuf],
+        symlinks: SymlinkMode,
+    ) -> Result<Vec<FileHandle>> {
+        let mut entries = Vec::new();
+        for path in file_paths {
+            if path.as_os_str() == STDIN_PATH {
+                entries.push(None);
+            } else {
+                entries.extend(walk_path(path, symlinks).await?.into_iter().map(Some));
+            }
+        }
+        let mut handles = Vec::with_capacity(entries.len());
+        for (idx, entry) in entries.into_iter().enumerate() {
+            let id = FileId::try_from(idx).map_err(|_| anyhow!("Too many files to send"))?;
+            let handle = match entry {
+                Some((path, name, EntryKind::File)) => FileHandle::new(id, path, name).await?,
+                Some((path, name, EntryKind::Symlink(target))) => {
+                    FileHandle::symlink(id, path, name, target)
+                }
+                Some((path, name, EntryKind::Directory)) => FileHandle::directory(id, path, name),
+                None => FileHandle::stdin(id),
+            };
+            handles.push(handle);
+        }
         Ok(handles)
     }
 }
 
-const SUFFIX: [&'static str; 9] = ["B", "KB", "MB", "GB", "TB", "PB", "EB", "ZB", "YB"];
+// Expands a path given on the command line into (local path, offered name, kind) entries.
+// Directories are walked recursively; names are relative to the directory's parent,
+// so sending `./project` offers `project/src/main.rs`. Paths given on the command
+// line are always followed, `symlinks` applies to links found inside directories.
+async fn walk_path(root: &Path, symlinks: SymlinkMode) -> Result<Vec<(PathBuf, String, EntryKind)>> {
+    let root_name = pathbuf_to_string(root)?;
+    if !tokio::fs::metadata(root).await?.is_dir() {
+        return Ok(vec![(root.to_path_buf(), root_name, EntryKind::File)]);
+    }
+    // Following links can lead back into a directory that was already walked
+    let mut visited = HashSet::from([tokio::fs::canonicalize(root).await?]);
+    let mut entries = Vec::new();
+    let mut stack = vec![(root.to_path_buf(), root_name)];
+    while let Some((dir, dir_name)) = stack.pop() {
+        let mut read_dir = tokio::fs::read_dir(&dir).await?;
+        let entries_before = entries.len();
+        let stack_before = stack.len();
+        while let Some(entry) = read_dir.next_entry().await? {
+            let child = entry.path();
+            let name = format!("{}/{}", dir_name, pathbuf_to_string(&child)?);
+            let is_symlink = entry.file_type().await?.is_symlink();
+            if is_symlink {
+                match symlinks {
+                    SymlinkMode::Skip => {
+                        debug!(path = ?child, "Skipping symlink");
+                        continue;
+                    }
+                    SymlinkMode::Preserve => {
+                        let target = tokio::fs::read_link(&child).await?;
+                        if target.is_absolute() {
+                            // Receivers only accept links that stay inside their output directory
+                            warn!(path = ?child, "Skipping symlink with absolute target");
+                            continue;
+                        }
+                        let target = target
+                            .to_str()
+                            .ok_or_else(|| anyhow!("Error converting {:?} to String", target))?
+                            .replace(std::path::MAIN_SEPARATOR, "/");
+                        entries.push((child, name, EntryKind::Symlink(target)));
+                        continue;
+                    }
+                    SymlinkMode::Follow => {}
+                }
+            }
+            let md = match tokio::fs::metadata(&child).await {
+                Ok(md) => md,
+                Err(_) if is_symlink => {
+                    warn!(path = ?child, "Skipping broken symlink");
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+            if !md.is_dir() {
+                entries.push((child, name, EntryKind::File));
+            } else if visited.insert(tokio::fs::canonicalize(&child).await?) {
+                stack.push((child, name));
+            } else {
+                warn!(path = ?child, "Skipping symlink loop or directory that was already sent");
+            }
+        }
+        // Directories only exist on the receiver through what is inside them
+        if entries.len() == entries_before && stack.len() == stack_before {
+            entries.push((dir, dir_name, EntryKind::Directory));
+        }
+    }
+    // Offer files in a stable order regardless of directory iteration order
+    entries.sort_unstable_by(|a, b| a.1.cmp(&b.1));
+    Ok(entries)
+}
+
+const SUFFIX: [&str; 9] = ["B", "KB", "MB", "GB", "TB", "PB", "EB", "ZB", "YB"];
 // Stolen: https://gitlab.com/forkbomb9/human_bytes-rs/-/blob/master/src/lib.rs
 pub fn to_size_string(size: u64) -> String {
     let size = size as f64;
@@ -148,7 +586,16 @@ pub fn to_size_string(size: u64) -> String {
     result
 }
 
-pub fn pathbuf_to_string(path: &PathBuf) -> Result<String> {
+pub fn pathbuf_to_string(path: &Path) -> Result<String> {
+    // Canonicalize so that paths like `.` or `dir/..` still yield a name
+    let canonical;
+    let path = match path.file_name() {
+        Some(_) => path,
+        None => {
+            canonical = path.canonicalize()?;
+            &canonical
+        }
+    };
     let filename = match path.file_name() {
         Some(s) => s,
         None => return Err(anyhow!("Could not get filename from file offer.")),
@@ -160,6 +607,12 @@ pub fn pathbuf_to_string(path: &PathBuf) -> Result<String> {
     }
 }
 
+pub fn append_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
 pub fn should_compress(filename: &str) -> bool {
     let skip_extensions = [
         // Images
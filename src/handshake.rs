@@ -1,8 +1,13 @@
-use crate::conf::{HANDSHAKE_MSG_SIZE, ID_SIZE};
+use crate::conf::{CONFIRMATION_TAG_SIZE, HANDSHAKE_MSG_SIZE, ID_SIZE, PROTOCOL_VERSION};
+use crate::crypto::{Cipher, Role};
+use crate::password::Code;
+use crate::verify::VerificationCode;
 
 use anyhow::{anyhow, Result};
 use blake2::{Blake2s256, Digest};
 use bytes::{Bytes, BytesMut};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
 use spake2::{Ed25519Group, Identity, Password, Spake2};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
@@ -13,10 +18,18 @@ pub struct Handshake {
     pub outbound_msg: Bytes,
 }
 
+// The SPAKE2 messages both sides saw
+pub struct Transcript {
+    id: Bytes,
+    ours: Bytes,
+    theirs: Bytes,
+}
+
 impl Handshake {
-    pub fn from_password(pw: &String) -> Result<(Handshake, spake2::Spake2<spake2::Ed25519Group>)> {
-        let password = Bytes::from(pw.clone());
-        let id = Handshake::pass_to_bytes(&pw);
+    pub fn from_password(pw: &str) -> Result<(Handshake, spake2::Spake2<spake2::Ed25519Group>)> {
+        let code = Code::parse(pw)?;
+        let password = Bytes::from(code.secret);
+        let id = Handshake::pass_to_bytes(&code.channel);
         let (s1, outbound_msg) =
             Spake2::<Ed25519Group>::start_symmetric(&Password::new(&password), &Identity::new(&id));
         let outbound_msg = Bytes::from(outbound_msg);
@@ -34,7 +47,7 @@ impl Handshake {
         Ok((Handshake { id, outbound_msg }, socket))
     }
 
-    pub fn to_bytes(self) -> Bytes {
+    pub fn into_bytes(self) -> Bytes {
         let mut buffer = BytesMut::with_capacity(ID_SIZE + HANDSHAKE_MSG_SIZE);
         buffer.extend_from_slice(&self.id);
         buffer.extend_from_slice(&self.outbound_msg);
@@ -45,9 +58,14 @@ impl Handshake {
         self,
         socket: TcpStream,
         s1: spake2::Spake2<spake2::Ed25519Group>,
-    ) -> Result<(TcpStream, Vec<u8>)> {
+    ) -> Result<(TcpStream, Vec<u8>, Transcript)> {
         let mut socket = socket;
-        let bytes = &self.to_bytes();
+        let transcript = Transcript {
+            id: self.id.clone(),
+            ours: self.outbound_msg.clone(),
+            theirs: Bytes::new(),
+        };
+        let bytes = self.into_bytes();
         socket.write_all(&bytes).await?;
         let mut buffer = [0; HANDSHAKE_MSG_SIZE];
         let n = socket.read_exact(&mut buffer).await?;
@@ -57,7 +75,150 @@ impl Handshake {
             Err(e) => return Err(anyhow!(e.to_string())),
         };
         debug!("Handshake successful");
-        return Ok((socket, key));
+        let transcript = Transcript {
+            theirs: response,
+            ..transcript
+        };
+        Ok((socket, key, transcript))
+    }
+
+    // Messages carry the protocol version, but the handshake before them
+    // doesn't. Both sides send theirs in the clear so a mismatch is reported
+    // as such, and the key confirmation covers it in case the relay changed it.
+    pub async fn check_version(socket: TcpStream) -> Result<TcpStream> {
+        let mut socket = socket;
+        socket.write_all(&PROTOCOL_VERSION.to_le_bytes()).await?;
+        let mut buffer = [0; 2];
+        socket.read_exact(&mut buffer).await?;
+        let version = u16::from_le_bytes(buffer);
+        if version != PROTOCOL_VERSION {
+            return Err(anyhow!(
+                "Protocol version mismatch: peer speaks v{}, this client speaks v{}. Make sure both sides are up to date.",
+                version,
+                PROTOCOL_VERSION
+            ));
+        }
+        Ok(socket)
+    }
+
+    // SPAKE2 succeeds even when the passwords differ, the keys just don't
+    // match. Each side proves it has the same key with a MAC over the
+    // transcript before anything else is sent.
+    pub async fn confirm_key(
+        socket: TcpStream,
+        key: &[u8],
+        transcript: &Transcript,
+        role: Role,
+    ) -> Result<TcpStream> {
+        let mut socket = socket;
+        let (ours, theirs): (&[u8], &[u8]) = match role {
+            Role::Sender => (b"ruck confirm sender", b"ruck confirm receiver"),
+            Role::Receiver => (b"ruck confirm receiver", b"ruck confirm sender"),
+        };
+        let tag = transcript.mac(key, ours, role).finalize().into_bytes();
+        socket.write_all(&tag).await?;
+        let mut buffer = [0; CONFIRMATION_TAG_SIZE];
+        socket.read_exact(&mut buffer).await?;
+        let expected = transcript.mac(key, theirs, role);
+        if expected.verify_slice(&buffer).is_err() {
+            return Err(anyhow!("Password mismatch or tampering detected"));
+        }
+        debug!("Key confirmed");
+        Ok(socket)
+    }
+
+    // Both sides send the ciphers they support, fastest first, and pick the
+    // same one from the two lists. The lists are mixed into the returned key,
+    // so if the relay altered either one, the first message fails to decrypt.
+    pub async fn negotiate_cipher(
+        socket: TcpStream,
+        key: &[u8],
+    ) -> Result<(TcpStream, Cipher, Vec<u8>)> {
+        let mut socket = socket;
+        let preferred = Cipher::preferred();
+        let ours: Vec<u8> = preferred.iter().map(|cipher| cipher.id()).collect();
+        let mut msg = Vec::with_capacity(1 + ours.len());
+        msg.push(ours.len() as u8);
+        msg.extend_from_slice(&ours);
+        socket.write_all(&msg).await?;
+
+        let len = socket.read_u8().await?;
+        let mut theirs = vec![0; len as usize];
+        socket.read_exact(&mut theirs).await?;
+        // Ciphers added by newer versions are ignored
+        let supported: Vec<Cipher> = theirs
+            .iter()
+            .filter_map(|&id| Cipher::from_id(id))
+            .collect();
+        let cipher = Cipher::choose(&preferred, &supported)
+            .ok_or_else(|| anyhow!("Peer supports none of our ciphers"))?;
+
+        let (first, second) = if ours <= theirs {
+            (&ours, &theirs)
+        } else {
+            (&theirs, &ours)
+        };
+        let mut hasher = Blake2s256::new();
+        hasher.update(key);
+        hasher.update(b"ruck cipher lists");
+        hasher.update([first.len() as u8]);
+        hasher.update(first);
+        hasher.update(second);
+        debug!(%cipher, "Cipher negotiated");
+        Ok((socket, cipher, hasher.finalize().to_vec()))
+    }
+
+    // Extra connections of a session are matched by the relay with an id derived
+    // from the session key, so only the two peers can compute it. Instead of a
+    // SPAKE2 message, both sides send a tag the other can check.
+    // Returns the handshake for an extra connection of the session and the tag
+    // the other side has to answer with. Each role sends its own tag, so a relay
+    // echoing a client's handshake back to it doesn't pass.
+    pub fn for_stream(key: &[u8], role: Role, index: u16) -> (Handshake, Bytes) {
+        let id = Bytes::copy_from_slice(&derive(key, b"ruck stream id", index));
+        let tag = |role| {
+            let label: &[u8] = match role {
+                Role::Sender => b"ruck sender stream tag",
+                Role::Receiver => b"ruck receiver stream tag",
+            };
+            let mut tag = BytesMut::with_capacity(HANDSHAKE_MSG_SIZE);
+            tag.extend_from_slice(&derive(key, label, index));
+            tag.resize(HANDSHAKE_MSG_SIZE, 0);
+            tag.freeze()
+        };
+        let peer = match role {
+            Role::Sender => Role::Receiver,
+            Role::Receiver => Role::Sender,
+        };
+        let handshake = Handshake {
+            id,
+            outbound_msg: tag(role),
+        };
+        (handshake, tag(peer))
+    }
+
+    pub async fn negotiate_stream(
+        self,
+        socket: TcpStream,
+        expected: Bytes,
+        index: u16,
+    ) -> Result<TcpStream> {
+        let mut socket = socket;
+        socket.write_all(&self.into_bytes()).await?;
+        let mut buffer = [0; HANDSHAKE_MSG_SIZE];
+        socket.read_exact(&mut buffer).await?;
+        if buffer[..] != expected[..] {
+            return Err(anyhow!("Stream {} failed to authenticate", index));
+        }
+        debug!(index, "Stream handshake successful");
+        Ok(socket)
+    }
+
+    // Both sides get the same code only if they share the key and saw the same
+    // handshake, so comparing it rules out a relay that guessed the password
+    pub fn verification_code(key: &[u8], transcript: &Transcript, role: Role) -> VerificationCode {
+        let mac = transcript.mac(key, b"ruck verification code", role);
+        VerificationCode::from_digest(&mac.finalize().into_bytes())
     }
 
     fn pass_to_bytes(password: &String) -> Bytes {
@@ -65,3 +226,28 @@ impl Handshake {
         BytesMut::from(&bytes[..]).freeze()
     }
 }
+
+impl Transcript {
+    // Covers the handshake messages in the same order on both sides
+    fn mac(&self, key: &[u8], label: &[u8], role: Role) -> Hmac<Sha256> {
+        let (sender_msg, receiver_msg) = match role {
+            Role::Sender => (&self.ours, &self.theirs),
+            Role::Receiver => (&self.theirs, &self.ours),
+        };
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+        mac.update(label);
+        mac.update(&PROTOCOL_VERSION.to_le_bytes());
+        mac.update(&self.id);
+        mac.update(sender_msg);
+        mac.update(receiver_msg);
+        mac
+    }
+}
+
+fn derive(key: &[u8], label: &[u8], index: u16) -> [u8; 32] {
+    let mut hasher = Blake2s256::new();
+    hasher.update(key);
+    hasher.update(label);
+    hasher.update(index.to_le_bytes());
+    hasher.finalize().into()
+}
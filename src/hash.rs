@@ -0,0 +1,120 @@
+use crate::conf::{BUFFER_SIZE, HASH_BLOCK_SIZE};
+use crate::sparse::data_regions;
+
+use anyhow::{anyhow, Result};
+use blake2::digest::consts::U32;
+use blake2::{Blake2b, Digest};
+use std::io::SeekFrom;
+use std::path::Path;
+use std::sync::OnceLock;
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+type Blake2b256 = Blake2b<U32>;
+pub type FileDigest = [u8; 32];
+
+// Files are hashed in blocks of HASH_BLOCK_SIZE, and the digest of a file is
+// BLAKE2b-256 over the BLAKE2b-256 digests of its blocks. Blocks of zeros all
+// have the same digest, so holes are hashed without reading or hashing them.
+#[derive(Clone, Default)]
+pub struct Hasher {
+    file: Blake2b256,
+    block: Blake2b256,
+    block_len: u64,
+}
+
+impl Hasher {
+    pub fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let n = data.len().min((HASH_BLOCK_SIZE - self.block_len) as usize);
+            self.block.update(&data[..n]);
+            self.block_len += n as u64;
+            data = &data[n..];
+            if self.block_len == HASH_BLOCK_SIZE {
+                self.finish_block();
+            }
+        }
+    }
+
+    fn finish_block(&mut self) {
+        let block = std::mem::take(&mut self.block);
+        self.file.update(block.finalize());
+        self.block_len = 0;
+    }
+}
+
+pub fn finalize(mut hasher: Hasher) -> FileDigest {
+    if hasher.block_len > 0 {
+        hasher.finish_block();
+    }
+    hasher.file.finalize().into()
+}
+
+pub fn to_hex(digest: &FileDigest) -> String {
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// Feeds `len` zero bytes, the contents of a hole, into the hasher
+pub fn update_zeros(hasher: &mut Hasher, len: u64) {
+    static ZERO_BLOCK: OnceLock<FileDigest> = OnceLock::new();
+    let zeros = [0u8; 64 * 1024];
+    let mut remaining = len;
+    while remaining > 0 {
+        // Whole blocks of zeros only cost their precomputed digest
+        if hasher.block_len == 0 && remaining >= HASH_BLOCK_SIZE {
+            let digest = ZERO_BLOCK.get_or_init(|| {
+                let mut block = Blake2b256::new();
+                for _ in 0..HASH_BLOCK_SIZE / zeros.len() as u64 {
+                    block.update(zeros);
+                }
+                block.finalize().into()
+            });
+            hasher.file.update(digest);
+            remaining -= HASH_BLOCK_SIZE;
+            continue;
+        }
+        let n = remaining
+            .min(zeros.len() as u64)
+            .min(HASH_BLOCK_SIZE - hasher.block_len) as usize;
+        hasher.update(&zeros[..n]);
+        remaining -= n as u64;
+    }
+}
+
+// Starts a hasher over the first `len` bytes of the file at `path`.
+// Holes are hashed as zeros without being read.
+pub async fn hash_file_prefix(path: &Path, len: u64) -> Result<Hasher> {
+    let mut hasher = Hasher::default();
+    if len == 0 {
+        return Ok(hasher);
+    }
+    let mut file = File::open(path).await?;
+    // Holes past the end would otherwise be taken for the missing bytes
+    if file.metadata().await?.len() < len {
+        return Err(anyhow!("File {:?} is shorter than expected", path));
+    }
+    let std_file = std::fs::File::open(path)?;
+    let regions = tokio::task::spawn_blocking(move || data_regions(&std_file, 0, len)).await??;
+    let mut buffer = vec![0u8; BUFFER_SIZE];
+    let mut offset: u64 = 0;
+    for region in regions.into_iter().chain(std::iter::once(len..len)) {
+        if region.start > offset {
+            update_zeros(&mut hasher, region.start - offset);
+            offset = region.start;
+        }
+        file.seek(SeekFrom::Start(offset)).await?;
+        let mut reader = (&mut file).take(region.end - offset);
+        loop {
+            let n = reader.read(&mut buffer).await?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buffer[..n]);
+            offset += n as u64;
+        }
+        if offset < region.end {
+            return Err(anyhow!("File {:?} is shorter than expected", path));
+        }
+    }
+    Ok(hasher)
+}
@@ -3,40 +3,76 @@ mod client;
 mod conf;
 mod connection;
 mod crypto;
+mod delta;
 mod file;
 mod handshake;
+mod hash;
 mod message;
 mod password;
+mod sanitize;
 mod server;
+mod sparse;
 mod ui;
+mod verify;
 
+use anyhow::anyhow;
 use clap::Parser;
 use cli::{Cli, Commands};
-use client::{receive, send};
+use conf::PASSWORD_ENV;
+use client::{receive, send, ReceiveOptions};
+use password::{resolve_password, validate_generate_pw};
 use server::serve;
 use std::error::Error;
 use tracing::debug;
 use tracing_subscriber::EnvFilter;
+use ui::resolve_text;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     // Initialize tracing with RUST_LOG env filter (defaults to "info")
     let filter = EnvFilter::try_from_default_env()
         .unwrap_or_else(|_| EnvFilter::new("ruck_relay=info"));
-    tracing_subscriber::fmt().with_env_filter(filter).init();
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .init();
 
     let args = Cli::parse();
     match &args.command {
-        Commands::Send { paths, password, relay } => {
+        Commands::Send {
+            paths, text, symlinks, streams, verify, password, password_file, code_format, relay,
+        } => {
             debug!("Sending {:?}", paths);
-            send(paths, password, relay).await?;
+            let text = resolve_text(text)?;
+            let password = resolve_password(password.clone(), password_file.as_deref(), false)?;
+            let password = validate_generate_pw(password, *code_format)?;
+            send(paths, &text, *symlinks, *streams, *verify, &password, relay).await?;
         }
-        Commands::Receive { password, relay } => {
+        Commands::Receive {
+            password, password_file, relay, stdout, out, collision, no_preserve, delta, verify,
+            yes,
+        } => {
             debug!("Receiving with provided password");
-            receive(password, relay).await?
+            let password = resolve_password(password.clone(), password_file.as_deref(), true)?
+                .ok_or_else(|| {
+                    anyhow!(
+                        "No password given. Pass it as an argument, with --password-file or in {}",
+                        PASSWORD_ENV
+                    )
+                })?;
+            let options = ReceiveOptions {
+                to_stdout: *stdout,
+                out_dir: out.clone(),
+                policy: *collision,
+                preserve_metadata: !no_preserve,
+                delta: *delta,
+                verify: *verify,
+                accept_all: *yes,
+            };
+            receive(&password, relay, &options).await?
         }
-        Commands::Relay { bind, max_clients, timeout } => {
-            serve(bind, *max_clients, *timeout).await?;
+        Commands::Relay { bind, max_clients, timeout, handshake_timeout, max_handshakes } => {
+            serve(bind, *max_clients, *timeout, *handshake_timeout, *max_handshakes).await?;
         }
     }
     Ok(())
@@ -1,7 +1,10 @@
-use crate::file::{ChunkHeader, CompressionType, FileOffer};
+use crate::conf::{PROTOCOL_MAGIC, PROTOCOL_VERSION};
+use crate::delta::{DeltaInstruction, Signatures};
+use crate::file::{ChunkHeader, ChunkRequest, CompressionType, FileId, FileOffer};
+use crate::hash::FileDigest;
 
 use anyhow::{anyhow, Result};
-use bytes::Bytes;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 use serde::{Deserialize, Serialize};
 use tokio::net::TcpStream;
 use tokio_util::codec::{Framed, LengthDelimitedCodec};
@@ -9,26 +12,63 @@ use tokio_util::codec::{Framed, LengthDelimitedCodec};
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Message {
     FileOffer(FileOfferPayload),
+    DigestRequest(DigestRequestPayload),
+    Digests(DigestsPayload),
     FileRequest(FileRequestPayload),
     FileTransferStart(FileTransferStartPayload),
     FileTransfer(FileTransferPayload),
-    FileTransferComplete,
+    FileHole(FileHolePayload),
+    FileSignatures(FileSignaturesPayload),
+    FileDelta(FileDeltaPayload),
+    FileTransferComplete(FileTransferCompletePayload),
+    Text(TextPayload),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TextPayload {
+    pub text: String,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct FileTransferStartPayload {
-    pub file_id: u8,
+    pub file_id: FileId,
     pub compression: CompressionType,
+    // Offset the sender will send from, zero if the receiver's prefix was rejected
+    pub start: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FileTransferCompletePayload {
+    pub file_id: FileId,
+    // Digest of the whole file, including any prefix the receiver resumed from
+    pub digest: FileDigest,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct FileRequestPayload {
-    pub chunks: Vec<ChunkHeader>,
+    pub chunks: Vec<ChunkRequest>,
+    // Number of connections the receiver agreed to, including this one
+    pub streams: u16,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct FileOfferPayload {
     pub files: Vec<FileOffer>,
+    // Number of connections the sender would like to use
+    pub streams: u16,
+}
+
+// Sent by the receiver for offered files it has a local copy of the same size,
+// before it decides what to request
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DigestRequestPayload {
+    pub files: Vec<FileId>,
+}
+
+// Digests of the whole files, in the order they were requested
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DigestsPayload {
+    pub digests: Vec<FileDigest>,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -37,11 +77,56 @@ pub struct FileTransferPayload {
     pub chunk: Bytes,
 }
 
+// A run of zeros that the sender's file doesn't store on disk
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FileHolePayload {
+    pub chunk_header: ChunkHeader,
+    pub len: u64,
+}
+
+// Sent by the receiver after its `FileRequest`, for each file it wants as a delta
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FileSignaturesPayload {
+    pub file_id: FileId,
+    pub signatures: Signatures,
+}
+
+// Rebuilds the next part of the file from the receiver's copy and new data
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FileDeltaPayload {
+    pub file_id: FileId,
+    pub instructions: Vec<DeltaInstruction>,
+}
+
+// Every message is prefixed with a magic number and the protocol version.
+// Older clients read the prefix as an out-of-range enum variant and fail to
+// deserialize, rather than misinterpreting newer messages.
+const HEADER_SIZE: usize = 4;
+
 impl Message {
     pub fn serialize(&self) -> Result<Bytes> {
-        bincode::serialize(&self).map(|vec| Ok(Bytes::from(vec)))?
+        let body = bincode::serialize(&self)?;
+        let mut buffer = BytesMut::with_capacity(HEADER_SIZE + body.len());
+        buffer.put_slice(PROTOCOL_MAGIC);
+        buffer.put_u16_le(PROTOCOL_VERSION);
+        buffer.put_slice(&body);
+        Ok(buffer.freeze())
     }
-    pub fn deserialize(bytes: Bytes) -> Result<Self> {
+    pub fn deserialize(mut bytes: Bytes) -> Result<Self> {
+        if bytes.len() < HEADER_SIZE || &bytes[..PROTOCOL_MAGIC.len()] != PROTOCOL_MAGIC {
+            return Err(anyhow!(
+                "Peer is using an incompatible ruck-relay version. Make sure both sides are up to date."
+            ));
+        }
+        bytes.advance(PROTOCOL_MAGIC.len());
+        let version = bytes.get_u16_le();
+        if version != PROTOCOL_VERSION {
+            return Err(anyhow!(
+                "Protocol version mismatch: peer speaks v{}, this client speaks v{}. Make sure both sides are up to date.",
+                version,
+                PROTOCOL_VERSION
+            ));
+        }
         match bincode::deserialize(bytes.as_ref()) {
             Ok(msg) => Ok(msg),
             Err(e) => Err(anyhow!(e.to_string())),
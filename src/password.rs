@@ -1,14 +1,108 @@
-use crate::conf::PASSWORD_LEN;
+use crate::conf::{CODE_CHANNEL_DIGITS, CODE_WORDS, PASSWORD_ENV, PASSWORD_LEN};
 
-use anyhow::{anyhow, Result};
-use rand::RngCore;
+use anyhow::{anyhow, Context, Result};
+use clap::ValueEnum;
+use rand::{Rng, RngCore};
+use std::io::IsTerminal;
+use std::path::Path;
+use std::sync::OnceLock;
 
 /// Base62 alphabet (alphanumeric, no ambiguous chars)
 const ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZabcdefghjkmnpqrstuvwxyz23456789";
 
-pub fn validate_generate_pw(pw: Option<String>) -> Result<String> {
-    let pass = pw.unwrap_or_else(generate_random_password);
-    match validate_pw(&pass) {
+/// The BIP39 English wordlist: 2048 sorted words, each identified by its first
+/// four letters, so they are hard to mishear.
+const WORDLIST: &str = include_str!("wordlist.txt");
+
+/// Format of generated passwords.
+///
+/// Every code starts with a 6 digit channel, like `482193-apple-river-tiger`.
+/// The relay pairs the two sides by the channel alone, so it can see it, but
+/// learns nothing about the rest. The ~20 bits of the channel only keep
+/// concurrent transfers on a relay apart.
+///
+/// The rest is the SPAKE2 password. SPAKE2 means it can't be guessed offline,
+/// and an attacker gets one online guess per transfer, which fails loudly.
+/// - Word codes use 3 words from a 2048 word list: 3 × 11 = 33 bits.
+/// - Random passwords are 16 base56 characters: about 93 bits.
+/// - Custom passwords get a generated channel, and are as strong as they are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum CodeFormat {
+    /// A numeric channel and words, easy to read aloud
+    Words,
+    /// A numeric channel and 16 random letters and digits
+    Random,
+}
+
+/// A code split into the part the relay uses to pair the two sides and
+/// the part only they know.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Code {
+    pub channel: String,
+    pub secret: String,
+}
+
+impl Code {
+    pub fn parse(pw: &str) -> Result<Code> {
+        let (channel, secret) = split_channel(pw.trim()).ok_or_else(|| {
+            anyhow!(
+                "Codes start with the {} digit channel shown by the sender, like 482193-...",
+                CODE_CHANNEL_DIGITS
+            )
+        })?;
+        // Words are accepted in any case, other passwords are used as typed
+        let lowercase = secret.to_lowercase();
+        let secret = if is_word_code(&lowercase) {
+            lowercase
+        } else {
+            secret.to_string()
+        };
+        Ok(Code {
+            channel: channel.to_string(),
+            secret,
+        })
+    }
+}
+
+/// Finds the password for either side. Passing it as an argument leaves it in
+/// shell history and `ps` output, so it can also come from `--password-file`,
+/// then `RUCK_PASSWORD`, then a prompt that doesn't echo, if `prompt` is set
+/// and stdin is a terminal.
+pub fn resolve_password(
+    password: Option<String>,
+    file: Option<&Path>,
+    prompt: bool,
+) -> Result<Option<String>> {
+    if password.is_some() {
+        return Ok(password);
+    }
+    if let Some(path) = file {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read password from {}", path.display()))?;
+        return Ok(Some(contents.trim_end_matches(['\r', '\n']).to_string()));
+    }
+    if let Some(password) = std::env::var(PASSWORD_ENV).ok().filter(|pw| !pw.is_empty()) {
+        return Ok(Some(password));
+    }
+    if prompt && std::io::stdin().is_terminal() {
+        return Ok(Some(rpassword::prompt_password("Code: ")?));
+    }
+    Ok(None)
+}
+
+/// Custom passwords get a channel if they don't start with one.
+pub fn validate_generate_pw(pw: Option<String>, format: CodeFormat) -> Result<String> {
+    let pass = match pw {
+        Some(pw) if split_channel(&pw).is_some() => pw,
+        Some(pw) => format!("{}-{}", generate_channel(), pw),
+        None => match format {
+            CodeFormat::Words => generate_word_code(),
+            CodeFormat::Random => format!("{}-{}", generate_channel(), generate_random_password()),
+        },
+    };
+    // Word codes get their strength from the wordlist instead of their length
+    let code = Code::parse(&pass)?;
+    match is_word_code(&code.secret) || validate_pw(&code.secret) {
         true => Ok(pass),
         false => Err(anyhow!(
             "Password too short (minimum {} characters).",
@@ -17,10 +111,48 @@ pub fn validate_generate_pw(pw: Option<String>) -> Result<String> {
     }
 }
 
-pub fn validate_pw(pw: &String) -> bool {
+pub fn validate_pw(pw: &str) -> bool {
     PASSWORD_LEN <= pw.len()
 }
 
+fn words() -> &'static [&'static str] {
+    static WORDS: OnceLock<Vec<&'static str>> = OnceLock::new();
+    WORDS.get_or_init(|| WORDLIST.lines().collect())
+}
+
+// Returns the channel and the rest of the code
+fn split_channel(pw: &str) -> Option<(&str, &str)> {
+    pw.split_once('-').filter(|(channel, _)| {
+        channel.len() == CODE_CHANNEL_DIGITS && channel.bytes().all(|b| b.is_ascii_digit())
+    })
+}
+
+fn is_word_code(secret: &str) -> bool {
+    let parts: Vec<&str> = secret.split('-').collect();
+    parts.len() == CODE_WORDS && parts.iter().all(|part| words().binary_search(part).is_ok())
+}
+
+fn generate_channel() -> String {
+    let channels = 10u32.pow(CODE_CHANNEL_DIGITS as u32);
+    format!(
+        "{:0width$}",
+        rand::thread_rng().gen_range(0..channels),
+        width = CODE_CHANNEL_DIGITS
+    )
+}
+
+/// Generate a word code with a random channel prefix, see [`CodeFormat`].
+pub fn generate_word_code() -> String {
+    let mut rng = rand::thread_rng();
+    let words = words();
+    let mut parts = Vec::with_capacity(1 + CODE_WORDS);
+    parts.push(generate_channel());
+    for _ in 0..CODE_WORDS {
+        parts.push(words[rng.gen_range(0..words.len())].to_string());
+    }
+    parts.join("-")
+}
+
 /// Generate a cryptographically secure random password.
 /// Uses base56 alphabet (alphanumeric minus ambiguous chars: 0, O, I, l, 1).
 /// 16 chars × ~5.8 bits/char ≈ 93 bits of entropy.
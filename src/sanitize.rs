@@ -0,0 +1,302 @@
+use crate::conf::{MAX_NAME_LEN, MAX_PATH_LEN};
+use crate::file::{EntryKind, FileOffer};
+
+use anyhow::{anyhow, Result};
+use std::path::{Component, Path, PathBuf};
+
+// Reserved device names on Windows, with or without an extension
+const DEVICE_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+// Offered paths come from the sender and can't be trusted. Every offer is
+// checked here before anything is prompted for or written to disk.
+pub fn sanitize_offers(offers: Vec<FileOffer>) -> Result<Vec<FileOffer>> {
+    offers
+        .into_iter()
+        .map(|offer| {
+            let path = sanitize_path(&offer.path)
+                .map_err(|e| anyhow!("Sender offered an unsafe path {:?}: {}", offer.path, e))?;
+            let kind = match offer.kind {
+                EntryKind::Symlink(target) => EntryKind::Symlink(
+                    sanitize_link_target(&path, &target).map_err(|e| {
+                        anyhow!("Sender offered an unsafe link {:?} -> {:?}: {}", path, target, e)
+                    })?,
+                ),
+                kind => kind,
+            };
+            Ok(FileOffer { path, kind, ..offer })
+        })
+        .collect()
+}
+
+// Returns the path as a normalized, '/' separated relative path,
+// or an error if it could refer to anything outside the output directory
+pub fn sanitize_path(path: &str) -> Result<String> {
+    if path.len() > MAX_PATH_LEN {
+        return Err(anyhow!("path is too long"));
+    }
+    if path.starts_with('/') || path.starts_with('\\') || has_drive_prefix(path) {
+        return Err(anyhow!("absolute paths are not allowed"));
+    }
+    let mut components = Vec::new();
+    for component in path.split(['/', '\\']) {
+        match component {
+            "" | "." => continue,
+            ".." => return Err(anyhow!("parent directory components are not allowed")),
+            _ => components.push(sanitize_component(component)?),
+        }
+    }
+    if components.is_empty() {
+        return Err(anyhow!("path is empty"));
+    }
+    Ok(components.join("/"))
+}
+
+// Returns the link target as a '/' separated relative path, or an error if
+// the link at `link_path` would point outside the output directory
+pub fn sanitize_link_target(link_path: &str, target: &str) -> Result<String> {
+    if target.len() > MAX_PATH_LEN {
+        return Err(anyhow!("link target is too long"));
+    }
+    if target.starts_with('/') || target.starts_with('\\') || has_drive_prefix(target) {
+        return Err(anyhow!("absolute link targets are not allowed"));
+    }
+    // Depth of the directory containing the link, below the output directory
+    let mut depth = link_path.split('/').count() - 1;
+    let mut components = Vec::new();
+    let mut descended = false;
+    for component in target.split(['/', '\\']) {
+        match component {
+            "" | "." => continue,
+            // `..` after a name would be resolved through that name, which may
+            // itself be a link to somewhere else
+            ".." if descended => {
+                return Err(anyhow!("parent components after a name are not allowed"))
+            }
+            ".." => {
+                depth = depth
+                    .checked_sub(1)
+                    .ok_or_else(|| anyhow!("link target escapes the output directory"))?;
+                components.push(component);
+            }
+            _ => {
+                components.push(sanitize_component(component)?);
+                depth += 1;
+                descended = true;
+            }
+        }
+    }
+    if components.is_empty() {
+        return Err(anyhow!("link target is empty"));
+    }
+    Ok(components.join("/"))
+}
+
+fn sanitize_component(component: &str) -> Result<&str> {
+    if component.len() > MAX_NAME_LEN {
+        return Err(anyhow!("file name is too long"));
+    }
+    if component.chars().any(char::is_control) {
+        return Err(anyhow!("control characters are not allowed"));
+    }
+    let stem = component.split('.').next().unwrap_or_default().trim_end();
+    if DEVICE_NAMES.iter().any(|name| stem.eq_ignore_ascii_case(name)) {
+        return Err(anyhow!("device names are not allowed"));
+    }
+    Ok(component)
+}
+
+fn has_drive_prefix(path: &str) -> bool {
+    let bytes = path.as_bytes();
+    bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':'
+}
+
+// Guards against symlinks already inside the output directory redirecting writes
+// elsewhere. Must be called before any missing parent directories are created.
+pub fn ensure_within(root: &Path, path: &Path) -> Result<()> {
+    if let Ok(md) = std::fs::symlink_metadata(path) {
+        if md.file_type().is_symlink() {
+            return Err(anyhow!("Refusing to write through symlink {:?}", path));
+        }
+    }
+    ensure_parent_within(root, path)
+}
+
+// Like `ensure_within`, but allows `path` itself to be a symlink
+pub fn ensure_parent_within(root: &Path, path: &Path) -> Result<()> {
+    let root = root.canonicalize()?;
+    // The closest existing ancestor decides where new directories would end up
+    let ancestor = path
+        .ancestors()
+        .skip(1)
+        .find(|ancestor| ancestor.exists())
+        .ok_or_else(|| anyhow!("Invalid output path {:?}", path))?;
+    if !ancestor.canonicalize()?.starts_with(&root) {
+        return Err(anyhow!("Refusing to write {:?} outside of {:?}", path, root));
+    }
+    Ok(())
+}
+
+// The target was only checked as text, but the link's parent may itself be
+// reached through links created earlier. Resolves it from where the link
+// really ends up, whether or not the target exists.
+pub fn ensure_link_within(root: &Path, link: &Path, target: &str) -> Result<()> {
+    let root = root.canonicalize()?;
+    let parent = link
+        .parent()
+        .ok_or_else(|| anyhow!("Invalid output path {:?}", link))?
+        .canonicalize()?;
+    let mut resolved = PathBuf::new();
+    for component in parent.join(target).components() {
+        match component {
+            Component::ParentDir => {
+                resolved.pop();
+            }
+            Component::CurDir => {}
+            component => resolved.push(component),
+        }
+    }
+    if !resolved.starts_with(&root) {
+        return Err(anyhow!(
+            "Refusing to create link {:?} pointing outside of {:?}",
+            link,
+            root
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_relative_paths() {
+        assert_eq!(sanitize_path("a.txt").unwrap(), "a.txt");
+        assert_eq!(sanitize_path("dir/sub/a.txt").unwrap(), "dir/sub/a.txt");
+        assert_eq!(sanitize_path("./dir//a.txt").unwrap(), "dir/a.txt");
+        assert_eq!(sanitize_path("dir\\a.txt").unwrap(), "dir/a.txt");
+        assert_eq!(sanitize_path("..hidden/a..b").unwrap(), "..hidden/a..b");
+        assert_eq!(sanitize_path("console.log").unwrap(), "console.log");
+    }
+
+    #[test]
+    fn rejects_absolute_paths() {
+        assert!(sanitize_path("/etc/passwd").is_err());
+        assert!(sanitize_path("\\Windows\\win.ini").is_err());
+        assert!(sanitize_path("C:\\Windows\\win.ini").is_err());
+        assert!(sanitize_path("c:relative").is_err());
+    }
+
+    #[test]
+    fn rejects_parent_components() {
+        assert!(sanitize_path("../.bashrc").is_err());
+        assert!(sanitize_path("../../.bashrc").is_err());
+        assert!(sanitize_path("dir/../../.bashrc").is_err());
+        assert!(sanitize_path("dir\\..\\..\\.bashrc").is_err());
+        assert!(sanitize_path("dir/..").is_err());
+    }
+
+    #[test]
+    fn rejects_device_names() {
+        assert!(sanitize_path("CON").is_err());
+        assert!(sanitize_path("dir/nul.txt").is_err());
+        assert!(sanitize_path("Com1.tar.gz").is_err());
+        assert!(sanitize_path("lpt9").is_err());
+        assert!(sanitize_path("aux .txt").is_err());
+    }
+
+    #[test]
+    fn rejects_control_characters() {
+        assert!(sanitize_path("a\nb.txt").is_err());
+        assert!(sanitize_path("a\0b.txt").is_err());
+        assert!(sanitize_path("dir/\x1b[31mred").is_err());
+        assert!(sanitize_path("a\u{7f}").is_err());
+    }
+
+    #[test]
+    fn rejects_overlong_names() {
+        let long_name = "a".repeat(MAX_NAME_LEN + 1);
+        assert!(sanitize_path(&long_name).is_err());
+        assert!(sanitize_path(&format!("dir/{}", long_name)).is_err());
+        let long_path = vec!["a"; MAX_PATH_LEN / 2 + 1].join("/");
+        assert!(sanitize_path(&long_path).is_err());
+        assert!(sanitize_path(&"a".repeat(MAX_NAME_LEN)).is_ok());
+    }
+
+    #[test]
+    fn rejects_empty_paths() {
+        assert!(sanitize_path("").is_err());
+        assert!(sanitize_path(".").is_err());
+        assert!(sanitize_path("./").is_err());
+    }
+
+    #[test]
+    fn accepts_link_targets_inside_root() {
+        assert_eq!(sanitize_link_target("a", "b.txt").unwrap(), "b.txt");
+        assert_eq!(sanitize_link_target("dir/a", "../b.txt").unwrap(), "../b.txt");
+        assert_eq!(sanitize_link_target("dir/sub/a", "./../../b").unwrap(), "../../b");
+        assert_eq!(sanitize_link_target("dir/a", "../dir/b").unwrap(), "../dir/b");
+    }
+
+    #[test]
+    fn rejects_link_targets_outside_root() {
+        assert!(sanitize_link_target("a", "/etc/passwd").is_err());
+        assert!(sanitize_link_target("a", "C:\\Windows").is_err());
+        assert!(sanitize_link_target("a", "../b").is_err());
+        assert!(sanitize_link_target("dir/a", "../../b").is_err());
+        assert!(sanitize_link_target("dir/a", "sub/../../../b").is_err());
+        assert!(sanitize_link_target("a", "").is_err());
+        assert!(sanitize_link_target("a", "b\nc").is_err());
+    }
+
+    #[test]
+    fn rejects_link_targets_through_other_links() {
+        // `evil/d/l -> ..` on its own stays inside, but `d/l/../..` from
+        // `evil/x` is resolved through that link and ends up above the root
+        assert!(sanitize_link_target("evil/d/l", "..").is_ok());
+        assert!(sanitize_link_target("evil/x", "d/l/../..").is_err());
+        assert!(sanitize_link_target("a", "dir/../b").is_err());
+        assert!(sanitize_link_target("dir/a", "../sub/../b").is_err());
+    }
+
+    #[test]
+    fn confines_writes_to_root() {
+        let root = std::env::temp_dir().join(format!("ruck-sanitize-{}", std::process::id()));
+        let outside = root.join("outside");
+        let inside = root.join("inside");
+        std::fs::create_dir_all(&outside).unwrap();
+        std::fs::create_dir_all(&inside).unwrap();
+        assert!(ensure_within(&inside, &inside.join("a.txt")).is_ok());
+        assert!(ensure_within(&inside, &inside.join("new/dir/a.txt")).is_ok());
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(&outside, inside.join("escape")).unwrap();
+            assert!(ensure_within(&inside, &inside.join("escape/a.txt")).is_err());
+            assert!(ensure_within(&inside, &inside.join("escape/new/a.txt")).is_err());
+            std::os::unix::fs::symlink(outside.join("a.txt"), inside.join("link.txt")).unwrap();
+            assert!(ensure_within(&inside, &inside.join("link.txt")).is_err());
+        }
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn confines_links_created_through_other_links() {
+        let root = std::env::temp_dir().join(format!("ruck-links-{}", std::process::id()));
+        let inside = root.join("inside");
+        std::fs::create_dir_all(inside.join("evil/a")).unwrap();
+        // `evil/a/d -> ..` was accepted on its own and points at `evil`
+        std::os::unix::fs::symlink("..", inside.join("evil/a/d")).unwrap();
+        let link = inside.join("evil/a/d/l");
+        assert!(sanitize_link_target("evil/a/d/l", "../../../zzz_escape").is_ok());
+        // Created through `d`, the link lives in `evil`, so the target is dangling
+        // and leaves the root
+        assert!(ensure_link_within(&inside, &link, "../../../zzz_escape").is_err());
+        assert!(ensure_link_within(&inside, &link, "../zzz_inside").is_ok());
+        assert!(ensure_link_within(&inside, &link, "../../zzz_escape").is_err());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -7,7 +7,7 @@ use std::sync::Arc;
 use tokio::io::{copy, AsyncWriteExt};
 use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::{broadcast, Mutex};
+use tokio::sync::{broadcast, Mutex, OwnedSemaphorePermit, Semaphore};
 use tokio::time::{timeout, Duration};
 use tracing::{debug, error, info, warn};
 
@@ -130,24 +130,49 @@ impl Client {
     }
 }
 
-pub async fn serve(bind: &str, max_clients: usize, timeout_secs: u64) -> Result<()> {
+pub async fn serve(
+    bind: &str,
+    max_clients: usize,
+    timeout_secs: u64,
+    handshake_timeout_secs: u64,
+    max_handshakes: usize,
+) -> Result<()> {
     let listener = TcpListener::bind(bind).await?;
     let config = ServerConfig { max_clients };
     info!(
         address = %bind,
         max_clients = max_clients,
         timeout_secs = timeout_secs,
+        handshake_timeout_secs = handshake_timeout_secs,
+        max_handshakes = max_handshakes,
         "Relay server listening"
     );
     let state = Arc::new(Mutex::new(Shared::new(config)));
     let (tx, _rx) = broadcast::channel::<Bytes>(BROADCAST_CHANNEL_CAPACITY);
+    let handshakes = Arc::new(Semaphore::new(max_handshakes));
     loop {
         let (stream, peer_addr) = listener.accept().await?;
+        // Connections that haven't sent a handshake yet don't count towards
+        // max_clients, so they are limited separately
+        let Ok(handshake_permit) = handshakes.clone().try_acquire_owned() else {
+            warn!(%peer_addr, max_handshakes, "Too many pending handshakes, dropping connection");
+            continue;
+        };
         let state = Arc::clone(&state);
         let tx = tx.clone();
         let peer_timeout = Duration::from_secs(timeout_secs);
+        let handshake_timeout = Duration::from_secs(handshake_timeout_secs);
         tokio::spawn(async move {
-            match handle_connection(state, stream, tx, peer_timeout).await {
+            let result = handle_connection(
+                state,
+                stream,
+                tx,
+                peer_timeout,
+                handshake_timeout,
+                handshake_permit,
+            )
+            .await;
+            match result {
                 Ok(_) => debug!(%peer_addr, "Connection complete"),
                 Err(err) => error!(%peer_addr, error = %err, "Connection error"),
             }
@@ -160,9 +185,18 @@ pub async fn handle_connection(
     socket: TcpStream,
     id_channel: IdChannelSender,
     peer_timeout: Duration,
+    handshake_timeout: Duration,
+    handshake_permit: OwnedSemaphorePermit,
 ) -> Result<()> {
-    socket.readable().await?;
-    let (handshake, socket) = Handshake::from_socket(socket).await?;
+    // A client that connects and then sends nothing, or only part of its
+    // handshake, would otherwise hold on to its socket forever
+    let (handshake, socket) = timeout(handshake_timeout, async {
+        socket.readable().await?;
+        Handshake::from_socket(socket).await
+    })
+    .await
+    .map_err(|_| anyhow!("Handshake timed out"))??;
+    drop(handshake_permit);
     let id = handshake.id.clone();
     let client = Client::new(id.clone(), state.clone(), socket).await?;
     id_channel.send(id.clone())?;
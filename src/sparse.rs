@@ -0,0 +1,48 @@
+use std::fs::File;
+use std::io;
+use std::ops::Range;
+
+// Returns the regions of `file` between `start` and `end` that hold data.
+// Everything in between is a hole, which reads as zeros and isn't stored on disk.
+#[cfg(target_os = "linux")]
+pub fn data_regions(file: &File, start: u64, end: u64) -> io::Result<Vec<Range<u64>>> {
+    use std::os::unix::io::AsRawFd;
+
+    let fd = file.as_raw_fd();
+    let mut regions = Vec::new();
+    let mut offset = start;
+    while offset < end {
+        // SAFETY: lseek only reads the offset of a file descriptor we own
+        let data = unsafe { libc::lseek(fd, offset as libc::off_t, libc::SEEK_DATA) };
+        if data < 0 {
+            let err = io::Error::last_os_error();
+            return match err.raw_os_error() {
+                // No data after `offset`, the rest of the file is a hole
+                Some(libc::ENXIO) => Ok(regions),
+                // Not supported here, treat the whole range as data
+                Some(libc::EINVAL) | Some(libc::EOPNOTSUPP) => {
+                    Ok(std::iter::once(start..end).collect())
+                }
+                _ => Err(err),
+            };
+        }
+        let data = data as u64;
+        if data >= end {
+            break;
+        }
+        // SAFETY: as above
+        let hole = unsafe { libc::lseek(fd, data as libc::off_t, libc::SEEK_HOLE) };
+        if hole < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let hole = (hole as u64).min(end);
+        regions.push(data..hole);
+        offset = hole;
+    }
+    Ok(regions)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn data_regions(_file: &File, start: u64, end: u64) -> io::Result<Vec<Range<u64>>> {
+    Ok(std::iter::once(start..end).collect())
+}
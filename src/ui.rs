@@ -1,46 +1,153 @@
-use crate::file::{to_size_string, FileOffer};
+use crate::crypto::Cipher;
+use crate::file::{to_size_string, EntryKind, FileOffer};
+use crate::verify::VerificationCode;
 
+use anyhow::{anyhow, Result};
+use colored::Colorize;
 use futures::prelude::*;
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use std::io::{IsTerminal, Read};
+use std::sync::OnceLock;
 
 use tokio::io::{self};
 
 use tokio_util::codec::{FramedRead, LinesCodec};
 
-pub async fn prompt_user_for_file_confirmation(file_offers: Vec<FileOffer>) -> Vec<FileOffer> {
+// With `accept_all` every offer is accepted without asking
+pub async fn prompt_user_for_file_confirmation(
+    file_offers: Vec<FileOffer>,
+    accept_all: bool,
+) -> Result<Vec<FileOffer>> {
+    if accept_all {
+        return Ok(file_offers);
+    }
     let mut stdin = FramedRead::new(io::stdin(), LinesCodec::new());
     let mut files = vec![];
     for file_offer in file_offers.into_iter() {
-        let mut reply = prompt_user_input(&mut stdin, &file_offer).await;
+        let mut reply = prompt_user_input(&mut stdin, &file_offer).await?;
         while reply.is_none() {
-            reply = prompt_user_input(&mut stdin, &file_offer).await;
+            reply = prompt_user_input(&mut stdin, &file_offer).await?;
         }
-        match reply {
-            Some(true) => files.push(file_offer),
-            _ => {}
+        if let Some(true) = reply {
+            files.push(file_offer);
         }
     }
-    files
+    Ok(files)
 }
 
+// Returns None for an answer that isn't yes or no, and an error once stdin is closed
 pub async fn prompt_user_input(
     stdin: &mut FramedRead<io::Stdin, LinesCodec>,
     file_offer: &FileOffer,
-) -> Option<bool> {
+) -> Result<Option<bool>> {
     let prompt_name = &file_offer.path;
-    println!(
-        "Accept {:?}? ({:?}). (Y/n)",
-        prompt_name,
-        to_size_string(file_offer.size)
-    );
+    let size = match (&file_offer.kind, file_offer.size) {
+        (EntryKind::Symlink(target), _) => format!("symlink to {}", target),
+        (EntryKind::Directory, _) => "empty directory".to_string(),
+        (_, Some(size)) => to_size_string(size),
+        (_, None) => "unknown size".to_string(),
+    };
+    eprintln!("Accept {:?}? ({:?}). (Y/n)", prompt_name, size);
     match stdin.next().await {
         Some(Ok(line)) => match line.as_str() {
-            "" | "Y" | "y" | "yes" | "Yes" | "YES" => Some(true),
-            "N" | "n" | "NO" | "no" | "No" => Some(false),
+            "" | "Y" | "y" | "yes" | "Yes" | "YES" => Ok(Some(true)),
+            "N" | "n" | "NO" | "no" | "No" => Ok(Some(false)),
             _ => {
-                println!("Invalid input. Please enter one of the following characters: [YyNn]");
-                return None;
+                eprintln!("Invalid input. Please enter one of the following characters: [YyNn]");
+                Ok(None)
             }
         },
-        _ => None,
+        Some(Err(e)) => Err(e.into()),
+        None => Err(anyhow!(
+            "Stdin closed before the offer was accepted. Pass --yes to accept without asking"
+        )),
+    }
+}
+
+// Progress is drawn on stderr, so stdout stays free for piped data. Bars share
+// one draw target, so transfers on several connections don't draw over each other.
+fn progress() -> &'static MultiProgress {
+    static PROGRESS: OnceLock<MultiProgress> = OnceLock::new();
+    PROGRESS.get_or_init(MultiProgress::new)
+}
+
+// Describes the secure channel once both sides are connected
+pub fn print_session_info(cipher: Cipher, code: &VerificationCode) {
+    eprintln!("{} {}", "Connected, encrypted with".dimmed(), cipher);
+    eprintln!("{} {}", "Verification code:".dimmed(), code);
+}
+
+// Reads a single line, leaving the rest of stdin for later prompts
+pub async fn confirm_verification_code() -> Result<()> {
+    loop {
+        eprintln!("Does the other side show the same verification code? (y/n)");
+        let line = tokio::task::spawn_blocking(|| {
+            let mut line = String::new();
+            std::io::stdin().read_line(&mut line).map(|n| (n, line))
+        })
+        .await??;
+        match line {
+            (0, _) => return Err(anyhow!("Verification code was not confirmed")),
+            (_, line) => match line.trim() {
+                "Y" | "y" | "yes" | "Yes" | "YES" => return Ok(()),
+                "N" | "n" | "NO" | "no" | "No" => {
+                    return Err(anyhow!("Verification codes don't match, stopping"))
+                }
+                _ => {
+                    eprintln!("Invalid input. Please enter one of the following characters: [YyNn]")
+                }
+            },
+        }
+    }
+}
+
+// Prints a line above any progress bars that are being drawn
+pub fn print_status(line: String) {
+    let progress = progress();
+    if progress.is_hidden() || progress.println(&line).is_err() {
+        eprintln!("{}", line);
+    }
+}
+
+pub fn transfer_progress_bar(size: Option<u64>, start: u64) -> ProgressBar {
+    let pb = match size {
+        Some(size) => {
+            let pb = ProgressBar::new(size);
+            pb.set_style(
+                ProgressStyle::default_bar()
+                    .template("{spinner:.green} [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta})")
+                    .unwrap()
+                    .progress_chars("#>-"),
+            );
+            pb
+        }
+        None => {
+            let pb = ProgressBar::new_spinner();
+            pb.set_style(
+                ProgressStyle::default_spinner()
+                    .template("{spinner:.green} {bytes} ({bytes_per_sec})")
+                    .unwrap(),
+            );
+            pb
+        }
+    };
+    pb.set_position(start);
+    progress().add(pb)
+}
+
+// `--text` without a value reads the message from piped stdin
+pub fn resolve_text(text: &Option<Option<String>>) -> Result<Option<String>> {
+    match text {
+        None => Ok(None),
+        Some(Some(text)) => Ok(Some(text.clone())),
+        Some(None) => {
+            let mut stdin = std::io::stdin();
+            if stdin.is_terminal() {
+                return Err(anyhow!("No text given. Pass it to --text or pipe it to stdin"));
+            }
+            let mut text = String::new();
+            stdin.read_to_string(&mut text)?;
+            Ok(Some(text.trim_end_matches('\n').to_string()))
+        }
     }
 }
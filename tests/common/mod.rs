@@ -0,0 +1,55 @@
+// Helpers shared by the integration tests, not every test uses all of them
+#![allow(dead_code)]
+
+use std::io::{BufRead, BufReader};
+use std::net::{TcpListener, TcpStream};
+use std::process::{Child, Command, Stdio};
+use std::thread::spawn;
+
+pub const BIN: &str = env!("CARGO_BIN_EXE_ruck-relay");
+
+// A relay server running on a free local port, killed when dropped
+pub struct Relay {
+    process: Child,
+    pub addr: String,
+}
+
+impl Relay {
+    pub fn start(args: &[&str]) -> Relay {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let addr = format!("127.0.0.1:{}", port);
+        let mut process = Command::new(BIN)
+            .args(["relay", "--bind", &addr])
+            .args(args)
+            .env("RUST_LOG", "ruck_relay=info")
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+
+        // Wait until it is listening, then keep draining its logs so it never
+        // blocks on a full pipe
+        let mut logs = BufReader::new(process.stderr.take().unwrap()).lines();
+        for line in logs.by_ref() {
+            if line.unwrap().contains("Relay server listening") {
+                break;
+            }
+        }
+        spawn(move || logs.for_each(drop));
+        Relay { process, addr }
+    }
+
+    pub fn connect(&self) -> TcpStream {
+        TcpStream::connect(&self.addr).unwrap()
+    }
+}
+
+impl Drop for Relay {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+    }
+}
@@ -0,0 +1,47 @@
+mod common;
+
+use common::Relay;
+use std::io::{ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+// Waits up to `limit` for the relay to hang up
+fn closed_within(socket: &mut TcpStream, limit: Duration) -> bool {
+    socket.set_read_timeout(Some(limit)).unwrap();
+    let mut buffer = [0; 64];
+    match socket.read(&mut buffer) {
+        Ok(0) => true,
+        Ok(_) => panic!("relay sent data to a client without a handshake"),
+        Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => false,
+        Err(_) => true,
+    }
+}
+
+#[test]
+fn idle_sockets_are_dropped() {
+    let relay = Relay::start(&["--handshake-timeout", "1"]);
+
+    // Part of a handshake, and then nothing
+    let mut partial = relay.connect();
+    partial.write_all(&[0; 10]).unwrap();
+    let mut silent = relay.connect();
+
+    let start = Instant::now();
+    assert!(closed_within(&mut partial, Duration::from_secs(10)));
+    assert!(closed_within(&mut silent, Duration::from_secs(10)));
+    assert!(start.elapsed() < Duration::from_secs(10));
+}
+
+#[test]
+fn pending_handshakes_are_limited() {
+    let relay = Relay::start(&["--handshake-timeout", "30", "--max-handshakes", "1"]);
+
+    let mut first = relay.connect();
+    // Give the relay time to accept the first connection
+    sleep(Duration::from_millis(500));
+    let mut second = relay.connect();
+
+    assert!(closed_within(&mut second, Duration::from_secs(5)));
+    assert!(!closed_within(&mut first, Duration::from_millis(500)));
+}
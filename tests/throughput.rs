@@ -0,0 +1,108 @@
+// Measures transfer throughput and the receiver's peak memory through a local
+// relay. Too slow for every run, so it is ignored by default:
+//
+//     cargo test --release --test throughput -- --ignored --nocapture
+//
+// RUCK_BENCH_MB sets the size of the file sent (512 MB by default).
+mod common;
+
+use common::{Relay, BIN};
+use rand::rngs::StdRng;
+use rand::{RngCore, SeedableRng};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+const CODE: &str = "123456-throughput-benchmark";
+
+fn spawn_client(args: &[&str]) -> Child {
+    Command::new(BIN)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap()
+}
+
+// Peak resident memory of a running process in KB, if the platform reports it
+fn peak_memory_kb(process: &Child) -> Option<u64> {
+    let status = std::fs::read_to_string(format!("/proc/{}/status", process.id())).ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmHWM:"))?;
+    line.split_whitespace().nth(1)?.parse().ok()
+}
+
+fn same_contents(a: &Path, b: &Path) -> bool {
+    let (mut a, mut b) = (File::open(a).unwrap(), File::open(b).unwrap());
+    let (mut buffer_a, mut buffer_b) = (vec![0u8; 1024 * 1024], vec![0u8; 1024 * 1024]);
+    loop {
+        let n = a.read(&mut buffer_a).unwrap();
+        if n == 0 {
+            return b.read(&mut buffer_b).unwrap() == 0;
+        }
+        if b.read_exact(&mut buffer_b[..n]).is_err() || buffer_a[..n] != buffer_b[..n] {
+            return false;
+        }
+    }
+}
+
+#[test]
+#[ignore]
+fn transfer_throughput() {
+    let mb: u64 = std::env::var("RUCK_BENCH_MB")
+        .ok()
+        .and_then(|mb| mb.parse().ok())
+        .unwrap_or(512);
+    let dir = std::env::temp_dir().join(format!("ruck-throughput-{}", std::process::id()));
+    let out = dir.join("out");
+    std::fs::create_dir_all(&out).unwrap();
+
+    // Random data, so compression doesn't flatter the numbers
+    let source = dir.join("data.bin");
+    let mut file = File::create(&source).unwrap();
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut buffer = vec![0u8; 1024 * 1024];
+    for _ in 0..mb {
+        rng.fill_bytes(&mut buffer);
+        file.write_all(&buffer).unwrap();
+    }
+    drop(file);
+
+    let relay = Relay::start(&[]);
+    let mut sender = spawn_client(&[
+        "send", "--relay", &relay.addr, "--password", CODE, source.to_str().unwrap(),
+    ]);
+    let start = Instant::now();
+    let mut receiver = spawn_client(&[
+        "receive", "--relay", &relay.addr, "--out", out.to_str().unwrap(), "--yes", CODE,
+    ]);
+    let mut peak_kb = 0;
+    let status = loop {
+        peak_kb = peak_kb.max(peak_memory_kb(&receiver).unwrap_or(0));
+        if let Some(status) = receiver.try_wait().unwrap() {
+            break status;
+        }
+        sleep(Duration::from_millis(20));
+    };
+    let elapsed = start.elapsed().as_secs_f64();
+    assert!(status.success());
+    assert!(sender.wait().unwrap().success());
+
+    assert!(same_contents(&source, &out.join("data.bin")));
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    println!(
+        "{} MB in {:.2}s: {:.1} MB/s, receiver peak memory {} MB",
+        mb,
+        elapsed,
+        mb as f64 / elapsed,
+        peak_kb / 1024
+    );
+    // Memory use shouldn't grow with the file size
+    if peak_kb > 0 {
+        assert!(peak_kb / 1024 < 256);
+    }
+}